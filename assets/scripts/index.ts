@@ -1,9 +1,41 @@
 import 'bootstrap';
 import Clipboard from 'clipboard';
+import { decode } from 'blurhash';
+
+const BLURHASH_SIZE = 32;
 
 window.addEventListener('DOMContentLoaded', initializePage);
 
 /// Initializes page content.
 function initializePage(e: Event): void {
     new Clipboard('.clipboard');
+    renderBlurhashPlaceholders();
+}
+
+/// Renders BlurHash placeholders as background images.
+function renderBlurhashPlaceholders(): void {
+    const canvas = document.createElement('canvas');
+    canvas.width = BLURHASH_SIZE;
+    canvas.height = BLURHASH_SIZE;
+    const context = canvas.getContext('2d');
+    if (context === null) {
+        return;
+    }
+
+    document.querySelectorAll<HTMLElement>('[data-blurhash]').forEach((element) => {
+        const hash = element.dataset.blurhash;
+        if (!hash) {
+            return;
+        }
+
+        try {
+            const pixels = decode(hash, BLURHASH_SIZE, BLURHASH_SIZE);
+            const imageData = context.createImageData(BLURHASH_SIZE, BLURHASH_SIZE);
+            imageData.data.set(pixels);
+            context.putImageData(imageData, 0, 0);
+            element.style.backgroundImage = `url(${canvas.toDataURL()})`;
+        } catch {
+            // Invalid hash; dominant color remains as placeholder
+        }
+    });
 }
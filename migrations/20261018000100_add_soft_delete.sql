@@ -0,0 +1,4 @@
+ALTER TABLE media
+  ADD COLUMN deleted_at TIMESTAMPTZ NULL DEFAULT NULL;
+
+CREATE INDEX IF NOT EXISTS media_deleted_at_index ON media (deleted_at);
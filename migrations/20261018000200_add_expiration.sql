@@ -0,0 +1,6 @@
+ALTER TABLE media
+  ADD COLUMN expires_at TIMESTAMPTZ NULL DEFAULT NULL,
+  ADD COLUMN max_views INTEGER NULL DEFAULT NULL,
+  ADD COLUMN view_count INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX IF NOT EXISTS media_expires_at_index ON media (expires_at);
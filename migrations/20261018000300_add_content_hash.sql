@@ -0,0 +1,11 @@
+ALTER TABLE media
+  ADD COLUMN content_hash CHAR(64) NULL DEFAULT NULL,
+  ADD COLUMN storage_id VARCHAR(128) NULL DEFAULT NULL;
+
+UPDATE media SET storage_id = hash_id WHERE storage_id IS NULL;
+
+ALTER TABLE media
+  ALTER COLUMN storage_id SET NOT NULL;
+
+CREATE INDEX IF NOT EXISTS media_content_hash_index ON media (content_hash);
+CREATE INDEX IF NOT EXISTS media_storage_id_index ON media (storage_id);
@@ -0,0 +1,5 @@
+ALTER TABLE media
+  ADD COLUMN exif_camera VARCHAR(256) NULL DEFAULT NULL,
+  ADD COLUMN exif_lens VARCHAR(256) NULL DEFAULT NULL,
+  ADD COLUMN exif_captured_at TIMESTAMP NULL DEFAULT NULL,
+  ADD COLUMN exif_orientation SMALLINT NULL DEFAULT NULL;
@@ -0,0 +1,3 @@
+ALTER TABLE media
+  ADD COLUMN blurhash VARCHAR(64) NULL DEFAULT NULL,
+  ADD COLUMN dominant_color CHAR(7) NULL DEFAULT NULL;
@@ -0,0 +1 @@
+CREATE SEQUENCE media_hash_id_sequence;
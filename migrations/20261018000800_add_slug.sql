@@ -0,0 +1,7 @@
+ALTER TABLE media
+  ADD COLUMN slug VARCHAR(64) NULL DEFAULT NULL UNIQUE;
+
+CREATE TABLE IF NOT EXISTS media_slug_aliases (
+  slug VARCHAR(64) NOT NULL PRIMARY KEY,
+  hash_id VARCHAR(128) NOT NULL REFERENCES media(hash_id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+ALTER TABLE media
+  ADD COLUMN variants TEXT[] NULL DEFAULT NULL;
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS media_tombstones (
+  key VARCHAR(128) NOT NULL PRIMARY KEY,
+  expired_at TIMESTAMPTZ NOT NULL
+);
@@ -1,28 +1,219 @@
 //! Contains database manipulation.
 
-use crate::{action::media::ValidatedImage, entity::Media};
+use crate::{
+    action::{
+        hash_id::HashIdGenerator,
+        media::{UploadOptions, ValidatedImage},
+    },
+    entity::{Media, Webhook, WebhookDelivery},
+};
 
 use anyhow::{anyhow, Result};
 use image::GenericImageView;
 use log::info;
-use once_cell::sync::Lazy;
-use rand::prelude::*;
-use sqlx::{error::DatabaseError, Error as SqlxError, PgPool};
+use sqlx::{error::DatabaseError, Connection, Error as SqlxError, FromRow, PgConnection, PgPool, Row};
+use std::fmt::{Display, Formatter, Result as FmtResult};
 use time::OffsetDateTime;
 
-static HASH_CHARS: Lazy<Box<[char]>> = Lazy::new(|| "0123456789abcdefghijklmnopqrstuvwxyz".chars().collect());
-const HASH_MIN_LENGTH: usize = 6;
-const MAX_RETRY: usize = 5;
+/// Unique constraint of `media.slug`.
+const SLUG_CONSTRAINT: &str = "media_slug_key";
+
+/// Error returned when a slug is already used by other media.
+#[derive(Debug)]
+pub struct SlugTakenError(pub String);
+
+impl Display for SlugTakenError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "Slug \"{}\" is already used", self.0)
+    }
+}
+
+impl std::error::Error for SlugTakenError {}
 
 /// Counts all records.
 pub async fn fetch_records_count(pool: &PgPool) -> Result<usize> {
+    let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM media WHERE deleted_at IS NULL;")
+        .fetch_one(pool)
+        .await?;
+    Ok(count as usize)
+}
+
+/// Counts all records including ones in the trash.
+pub async fn fetch_all_records_count(pool: &PgPool) -> Result<usize> {
     let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM media;").fetch_one(pool).await?;
     Ok(count as usize)
 }
 
+/// Advances the hash ID sequence to at least `sequence`, so that the next number is greater than it.
+/// The sequence is never moved backwards.
+pub async fn advance_hash_id_sequence(conn: &mut PgConnection, sequence: i64) -> Result<()> {
+    sqlx::query("SELECT setval('media_hash_id_sequence', GREATEST($1, (SELECT last_value FROM media_hash_id_sequence)));")
+        .bind(sequence)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Fetches a batch of all media records (including ones in the trash) ordered by hash ID.
+pub async fn fetch_media_batch(pool: &PgPool, after: Option<&str>, limit: usize) -> Result<Vec<Media>> {
+    let media = sqlx::query_as("SELECT * FROM media WHERE ($1::VARCHAR IS NULL OR hash_id > $1) ORDER BY hash_id LIMIT $2;")
+        .bind(after)
+        .bind(limit as i64)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(media)
+}
+
 /// Fetches a media record.
 pub async fn fetch_media(pool: &PgPool, hash_id: &str) -> Result<Option<Media>> {
-    let media = sqlx::query_as("SELECT * FROM media WHERE hash_id = $1;")
+    let media = sqlx::query_as("SELECT * FROM media WHERE hash_id = $1 AND deleted_at IS NULL;")
+        .bind(hash_id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(media)
+}
+
+/// Fetches a media record by hash ID or current slug.
+pub async fn fetch_media_by_key(pool: &PgPool, key: &str) -> Result<Option<Media>> {
+    let media = sqlx::query_as("SELECT * FROM media WHERE (hash_id = $1 OR slug = $1) AND deleted_at IS NULL;")
+        .bind(key)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(media)
+}
+
+/// Fetches a media record by its former slug.
+pub async fn fetch_media_by_slug_alias(pool: &PgPool, slug: &str) -> Result<Option<Media>> {
+    let media = sqlx::query_as(
+        r#"
+        SELECT media.* FROM media_slug_aliases
+        INNER JOIN media ON media.hash_id = media_slug_aliases.hash_id
+        WHERE media_slug_aliases.slug = $1 AND media.deleted_at IS NULL;
+        "#,
+    )
+    .bind(slug)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(media)
+}
+
+/// Fetches all former slugs as pairs of hash ID and slug.
+pub async fn fetch_all_slug_aliases(pool: &PgPool) -> Result<Vec<(String, String)>> {
+    let aliases = sqlx::query_as("SELECT hash_id, slug FROM media_slug_aliases ORDER BY hash_id, slug;")
+        .fetch_all(pool)
+        .await?;
+
+    Ok(aliases)
+}
+
+/// Checks whether the identifier is used as a hash ID, slug or former slug by media other than `owner`.
+pub async fn is_identifier_taken(conn: &mut PgConnection, identifier: &str, owner: Option<&str>) -> Result<bool> {
+    let (taken,): (bool,) = sqlx::query_as(
+        r#"
+        SELECT
+            EXISTS (SELECT 1 FROM media WHERE (hash_id = $1 OR slug = $1) AND hash_id IS DISTINCT FROM $2)
+            OR EXISTS (SELECT 1 FROM media_slug_aliases WHERE slug = $1 AND hash_id IS DISTINCT FROM $2);
+        "#,
+    )
+    .bind(identifier)
+    .bind(owner)
+    .fetch_one(conn)
+    .await?;
+
+    Ok(taken)
+}
+
+/// Fetches a media record which has the same content.
+pub async fn fetch_media_by_content_hash(pool: &PgPool, content_hash: &str) -> Result<Option<Media>> {
+    let media = sqlx::query_as("SELECT * FROM media WHERE content_hash = $1 AND deleted_at IS NULL ORDER BY uploaded LIMIT 1;")
+        .bind(content_hash)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(media)
+}
+
+/// Fetches media which look similar to the given one, sorted by Hamming distance of perceptual hashes.
+pub async fn fetch_similar_media_list(
+    pool: &PgPool,
+    media: &Media,
+    threshold: u32,
+    include_private: bool,
+    limit: usize,
+) -> Result<Vec<(Media, u32)>> {
+    let perceptual_hash = match media.perceptual_hash {
+        Some(h) => h,
+        None => return Ok(vec![]),
+    };
+
+    // Hamming distance is the number of 1s in the XOR, counted in SQL so that only the nearest rows are fetched
+    let rows = sqlx::query(
+        r#"
+        SELECT * FROM (
+            SELECT media.*, LENGTH(REPLACE((perceptual_hash # $2)::BIT(64)::TEXT, '0', '')) AS distance
+            FROM media
+            WHERE
+                perceptual_hash IS NOT NULL AND hash_id <> $1
+                AND deleted_at IS NULL AND (is_private = FALSE OR $3)
+                AND (expires_at IS NULL OR expires_at > $4)
+                AND (max_views IS NULL OR view_count < max_views)
+        ) AS candidates
+        WHERE distance <= $5
+        ORDER BY distance, uploaded DESC
+        LIMIT $6;
+        "#,
+    )
+    .bind(&media.hash_id)
+    .bind(perceptual_hash)
+    .bind(include_private)
+    .bind(OffsetDateTime::now_local()?)
+    .bind(threshold as i32)
+    .bind(limit as i64)
+    .fetch_all(pool)
+    .await?;
+
+    let similar = rows
+        .iter()
+        .map(|row| Ok((Media::from_row(row)?, row.try_get::<i32, _>("distance")? as u32)))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(similar)
+}
+
+/// Counts media records which refer to the stored files.
+pub async fn count_storage_references(conn: &mut PgConnection, storage_id: &str) -> Result<usize> {
+    let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM media WHERE storage_id = $1;")
+        .bind(storage_id)
+        .fetch_one(conn)
+        .await?;
+    Ok(count as usize)
+}
+
+/// Locks media records which refer to the stored files until the transaction ends.
+pub async fn lock_storage_references(conn: &mut PgConnection, storage_id: &str) -> Result<()> {
+    sqlx::query("SELECT hash_id FROM media WHERE storage_id = $1 FOR UPDATE;")
+        .bind(storage_id)
+        .fetch_all(conn)
+        .await?;
+    Ok(())
+}
+
+/// Locks a media record against deletion until the transaction ends.
+/// Returns `false` if the record no longer exists.
+pub async fn lock_media_record(conn: &mut PgConnection, hash_id: &str) -> Result<bool> {
+    let locked: Option<(String,)> = sqlx::query_as("SELECT hash_id FROM media WHERE hash_id = $1 FOR SHARE;")
+        .bind(hash_id)
+        .fetch_optional(conn)
+        .await?;
+    Ok(locked.is_some())
+}
+
+/// Fetches a media record in the trash.
+pub async fn fetch_trashed_media(pool: &PgPool, hash_id: &str) -> Result<Option<Media>> {
+    let media = sqlx::query_as("SELECT * FROM media WHERE hash_id = $1 AND deleted_at IS NOT NULL;")
         .bind(hash_id)
         .fetch_optional(pool)
         .await?;
@@ -33,31 +224,144 @@ pub async fn fetch_media(pool: &PgPool, hash_id: &str) -> Result<Option<Media>>
 /// Fetches media list.
 pub async fn fetch_media_list(pool: &PgPool, latest: Option<OffsetDateTime>, limit: usize) -> Result<Vec<Media>> {
     let query_str = if latest.is_some() {
-        "SELECT * FROM media WHERE uploaded < $1 AND is_private = FALSE ORDER BY uploaded DESC LIMIT $2;"
+        r#"
+        SELECT * FROM media
+        WHERE
+            uploaded < $1 AND is_private = FALSE AND deleted_at IS NULL
+            AND (expires_at IS NULL OR expires_at > $3)
+            AND (max_views IS NULL OR view_count < max_views)
+        ORDER BY uploaded DESC LIMIT $2;
+        "#
     } else {
-        "SELECT * FROM media WHERE is_private = FALSE ORDER BY uploaded DESC LIMIT $2;"
+        r#"
+        SELECT * FROM media
+        WHERE
+            is_private = FALSE AND deleted_at IS NULL
+            AND (expires_at IS NULL OR expires_at > $3)
+            AND (max_views IS NULL OR view_count < max_views)
+        ORDER BY uploaded DESC LIMIT $2;
+        "#
     };
-    let media = sqlx::query_as(query_str).bind(latest).bind(limit as i64).fetch_all(pool).await?;
+    let media = sqlx::query_as(query_str)
+        .bind(latest)
+        .bind(limit as i64)
+        .bind(OffsetDateTime::now_local()?)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(media)
+}
+
+/// Fetches media list in the trash.
+pub async fn fetch_trashed_media_list(pool: &PgPool) -> Result<Vec<Media>> {
+    let media = sqlx::query_as("SELECT * FROM media WHERE deleted_at IS NOT NULL ORDER BY deleted_at DESC;")
+        .fetch_all(pool)
+        .await?;
 
     Ok(media)
 }
 
+/// Fetches media which have been in the trash since before `deleted_before`.
+pub async fn fetch_purgeable_media_list(pool: &PgPool, deleted_before: OffsetDateTime) -> Result<Vec<Media>> {
+    let media = sqlx::query_as("SELECT * FROM media WHERE deleted_at < $1;")
+        .bind(deleted_before)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(media)
+}
+
+/// Fetches media which have expired by date or view count.
+pub async fn fetch_expired_media_list(pool: &PgPool, now: OffsetDateTime) -> Result<Vec<Media>> {
+    let media = sqlx::query_as("SELECT * FROM media WHERE expires_at <= $1 OR view_count >= max_views;")
+        .bind(now)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(media)
+}
+
+/// Records hash ID and slug of expired media, so that their pages keep telling that they have expired.
+pub async fn insert_media_tombstones(pool: &PgPool, media: &Media, expired_at: OffsetDateTime) -> Result<()> {
+    let keys: Vec<&str> = [Some(media.hash_id.as_str()), media.slug.as_deref()]
+        .into_iter()
+        .flatten()
+        .collect();
+    sqlx::query("INSERT INTO media_tombstones (key, expired_at) SELECT UNNEST($1::VARCHAR[]), $2 ON CONFLICT (key) DO NOTHING;")
+        .bind(&keys)
+        .bind(expired_at)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Removes records of media purged by expiration before the date, and returns the number of removed records.
+pub async fn remove_media_tombstones(pool: &PgPool, expired_before: OffsetDateTime) -> Result<u64> {
+    let result = sqlx::query("DELETE FROM media_tombstones WHERE expired_at < $1;")
+        .bind(expired_before)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Checks whether the hash ID or slug belonged to media purged by expiration.
+pub async fn is_media_tombstoned(pool: &PgPool, key: &str) -> Result<bool> {
+    let (tombstoned,): (bool,) = sqlx::query_as("SELECT EXISTS (SELECT 1 FROM media_tombstones WHERE key = $1);")
+        .bind(key)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(tombstoned)
+}
+
 /// Reserves a database record for media.
-pub async fn reserve_media_record(pool: &PgPool, validated_image: &ValidatedImage, thumbnail: bool, private: bool) -> Result<Media> {
+/// If `storage_id` is set, the record shares the stored files with existing media.
+/// Each attempt runs in a savepoint, so this can be called inside a transaction.
+pub async fn reserve_media_record(
+    conn: &mut PgConnection,
+    validated_image: &ValidatedImage,
+    thumbnail: bool,
+    options: &UploadOptions,
+    generator: &HashIdGenerator,
+    storage_id: Option<&str>,
+) -> Result<Media> {
     let extension = validated_image
         .format
         .extensions_str()
         .get(0)
         .expect("Validated image should have extension");
     let (width, height) = validated_image.image.dimensions();
+    let exif = validated_image.exif.clone().unwrap_or_default();
+    let uploaded = match options.uploaded_at {
+        Some(dt) => dt,
+        None => OffsetDateTime::now_local()?,
+    };
 
-    for i in 0..MAX_RETRY {
-        let length = HASH_MIN_LENGTH + i;
-        info!("Attempting {}... ({} chars)", i + 1, length);
+    // Checked in the transaction so that the slug can't be taken between validating and inserting
+    if let Some(slug) = options.slug.as_deref() {
+        if is_identifier_taken(&mut *conn, slug, None).await? {
+            return Err(SlugTakenError(slug.to_string()).into());
+        }
+    }
 
-        let chars = HASH_CHARS.as_ref();
-        let hash: String = chars.choose_multiple(&mut thread_rng(), length).collect();
+    for i in 0..generator.max_attempts() {
+        let sequence = if generator.needs_sequence() {
+            let (sequence,): (i64,) = sqlx::query_as("SELECT nextval('media_hash_id_sequence');")
+                .fetch_one(&mut *conn)
+                .await?;
+            Some(sequence as u64)
+        } else {
+            None
+        };
+        let hash = generator.generate(i, sequence);
+        info!("Attempting {}... ({})", i + 1, hash);
+        if is_identifier_taken(&mut *conn, &hash, None).await? {
+            continue;
+        }
 
+        let mut savepoint = conn.begin().await?;
         let query_result = sqlx::query_as(
             r#"
             INSERT INTO media (
@@ -68,25 +372,58 @@ pub async fn reserve_media_record(pool: &PgPool, validated_image: &ValidatedImag
                 width,
                 height,
                 filesize,
-                uploaded
+                uploaded,
+                expires_at,
+                max_views,
+                content_hash,
+                storage_id,
+                perceptual_hash,
+                exif_camera,
+                exif_lens,
+                exif_captured_at,
+                exif_orientation,
+                blurhash,
+                dominant_color,
+                comment,
+                slug
             ) VALUES (
-                $1, $2, $3, $4, $5, $6, $7, $8
+                $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, COALESCE($12, $1), $13, $14, $15, $16, $17, $18, $19, $20, $21
             ) RETURNING *;
         "#,
         )
         .bind(hash)
         .bind(extension)
         .bind(thumbnail)
-        .bind(private)
+        .bind(options.private)
         .bind(width as i32)
         .bind(height as i32)
         .bind(validated_image.filesize as i32)
-        .bind(OffsetDateTime::now_local()?)
-        .fetch_one(pool)
+        .bind(uploaded)
+        .bind(options.expires_at)
+        .bind(options.max_views)
+        .bind(&validated_image.content_hash)
+        .bind(storage_id)
+        .bind(validated_image.perceptual_hash)
+        .bind(exif.camera.as_deref())
+        .bind(exif.lens.as_deref())
+        .bind(exif.captured_at)
+        .bind(exif.orientation.map(|o| o as i16))
+        .bind(validated_image.blurhash.as_deref())
+        .bind(&validated_image.dominant_color)
+        .bind(options.comment.as_deref())
+        .bind(options.slug.as_deref())
+        .fetch_one(&mut *savepoint)
         .await;
 
         match query_result {
-            Ok(media) => return Ok(media),
+            Ok(media) => {
+                savepoint.commit().await?;
+                return Ok(media);
+            }
+            // Retrying with another hash ID never resolves a slug conflict
+            Err(SqlxError::Database(sql_err)) if sql_err.constraint() == Some(SLUG_CONSTRAINT) => {
+                return Err(SlugTakenError(options.slug.clone().unwrap_or_default()).into());
+            }
             Err(SqlxError::Database(sql_err)) if is_conflicting(sql_err.as_ref()) => continue,
             Err(err) => return Err(err.into()),
         }
@@ -95,6 +432,70 @@ pub async fn reserve_media_record(pool: &PgPool, validated_image: &ValidatedImag
     Err(anyhow!("Failed to create record"))
 }
 
+/// Inserts a media record as is, used by restoring.
+pub async fn insert_media_record(conn: &mut PgConnection, media: &Media) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO media (
+            hash_id,
+            extension,
+            has_thumbnail,
+            is_private,
+            width,
+            height,
+            filesize,
+            comment,
+            uploaded,
+            deleted_at,
+            expires_at,
+            max_views,
+            view_count,
+            content_hash,
+            storage_id,
+            perceptual_hash,
+            exif_camera,
+            exif_lens,
+            exif_captured_at,
+            exif_orientation,
+            blurhash,
+            dominant_color,
+            slug,
+            variants
+        ) VALUES (
+            $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22, $23, $24
+        );
+    "#,
+    )
+    .bind(&media.hash_id)
+    .bind(&media.extension)
+    .bind(media.has_thumbnail)
+    .bind(media.is_private)
+    .bind(media.width)
+    .bind(media.height)
+    .bind(media.filesize)
+    .bind(media.comment.as_deref())
+    .bind(media.uploaded)
+    .bind(media.deleted_at)
+    .bind(media.expires_at)
+    .bind(media.max_views)
+    .bind(media.view_count)
+    .bind(media.content_hash.as_deref())
+    .bind(&media.storage_id)
+    .bind(media.perceptual_hash)
+    .bind(media.exif_camera.as_deref())
+    .bind(media.exif_lens.as_deref())
+    .bind(media.exif_captured_at)
+    .bind(media.exif_orientation)
+    .bind(media.blurhash.as_deref())
+    .bind(media.dominant_color.as_deref())
+    .bind(media.slug.as_deref())
+    .bind(media.variants.as_deref())
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
 /// Updates media information.
 pub async fn update_media_record(pool: &PgPool, hash_id: &str, private: bool, comment: &str) -> Result<Media> {
     let new_record = sqlx::query_as(
@@ -113,16 +514,296 @@ pub async fn update_media_record(pool: &PgPool, hash_id: &str, private: bool, co
     Ok(new_record)
 }
 
+/// Changes the slug of media.
+/// The former slug is kept as an alias redirecting to the media.
+/// Fails with `SlugTakenError` if other media uses the new slug.
+pub async fn update_media_slug(pool: &PgPool, media: &Media, new_slug: Option<&str>) -> Result<Media> {
+    let mut transaction = pool.begin().await?;
+
+    if let Some(new_slug) = new_slug {
+        if is_identifier_taken(&mut transaction, new_slug, Some(&media.hash_id)).await? {
+            return Err(SlugTakenError(new_slug.to_string()).into());
+        }
+        sqlx::query("DELETE FROM media_slug_aliases WHERE slug = $1 AND hash_id = $2;")
+            .bind(new_slug)
+            .bind(&media.hash_id)
+            .execute(&mut *transaction)
+            .await?;
+    }
+    let new_record = match sqlx::query_as("UPDATE media SET slug = $1 WHERE hash_id = $2 RETURNING *;")
+        .bind(new_slug)
+        .bind(&media.hash_id)
+        .fetch_one(&mut *transaction)
+        .await
+    {
+        Ok(record) => record,
+        Err(SqlxError::Database(sql_err)) if sql_err.constraint() == Some(SLUG_CONSTRAINT) => {
+            return Err(SlugTakenError(new_slug.unwrap_or_default().to_string()).into());
+        }
+        Err(err) => return Err(err.into()),
+    };
+    match media.slug.as_deref() {
+        Some(old_slug) if Some(old_slug) != new_slug => {
+            insert_slug_alias(&mut transaction, old_slug, &media.hash_id).await?;
+        }
+        _ => (),
+    }
+
+    transaction.commit().await?;
+    Ok(new_record)
+}
+
+/// Records a former slug of media.
+pub async fn insert_slug_alias(conn: &mut PgConnection, slug: &str, hash_id: &str) -> Result<()> {
+    sqlx::query("INSERT INTO media_slug_aliases (slug, hash_id) VALUES ($1, $2);")
+        .bind(slug)
+        .bind(hash_id)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Fetches media whose derivatives have not been generated yet, one per stored file.
+pub async fn fetch_media_pending_variants(pool: &PgPool, limit: usize) -> Result<Vec<Media>> {
+    let media_list = sqlx::query_as(
+        r#"
+        SELECT DISTINCT ON (storage_id) * FROM media
+        WHERE variants IS NULL AND deleted_at IS NULL
+        ORDER BY storage_id, uploaded
+        LIMIT $1;
+        "#,
+    )
+    .bind(limit as i64)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(media_list)
+}
+
+/// Updates available derivatives, for all media sharing the stored files.
+pub async fn update_media_variants(pool: &PgPool, storage_id: &str, variants: &[String]) -> Result<()> {
+    sqlx::query("UPDATE media SET variants = $1 WHERE storage_id = $2;")
+        .bind(variants)
+        .bind(storage_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Updates information derived from stored files, for all media sharing them.
+pub async fn update_media_derivatives(
+    pool: &PgPool,
+    storage_id: &str,
+    has_thumbnail: bool,
+    perceptual_hash: i64,
+    blurhash: Option<&str>,
+    dominant_color: &str,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+        UPDATE media
+        SET has_thumbnail = $1, perceptual_hash = $2, blurhash = $3, dominant_color = $4
+        WHERE storage_id = $5;
+        "#,
+    )
+    .bind(has_thumbnail)
+    .bind(perceptual_hash)
+    .bind(blurhash)
+    .bind(dominant_color)
+    .bind(storage_id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Updates recorded filesize and dimensions of media.
+pub async fn update_media_file_info(pool: &PgPool, hash_id: &str, filesize: i32, width: i32, height: i32) -> Result<()> {
+    sqlx::query("UPDATE media SET filesize = $1, width = $2, height = $3 WHERE hash_id = $4;")
+        .bind(filesize)
+        .bind(width)
+        .bind(height)
+        .bind(hash_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Updates thumbnail flag of media.
+pub async fn update_media_thumbnail_flag(pool: &PgPool, hash_id: &str, has_thumbnail: bool) -> Result<()> {
+    sqlx::query("UPDATE media SET has_thumbnail = $1 WHERE hash_id = $2;")
+        .bind(has_thumbnail)
+        .bind(hash_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Counts up the view of media.
+/// Returns `false` if the media has already reached its maximum view count.
+pub async fn consume_media_view(pool: &PgPool, hash_id: &str) -> Result<bool> {
+    let result = sqlx::query(
+        r#"
+        UPDATE media
+        SET view_count = view_count + 1
+        WHERE hash_id = $1 AND (max_views IS NULL OR view_count < max_views);
+        "#,
+    )
+    .bind(hash_id)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Moves a record into the trash.
+pub async fn trash_media_record(pool: &PgPool, hash_id: &str) -> Result<()> {
+    sqlx::query("UPDATE media SET deleted_at = $1 WHERE hash_id = $2 AND deleted_at IS NULL;")
+        .bind(OffsetDateTime::now_local()?)
+        .bind(hash_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Restores a record from the trash.
+pub async fn restore_media_record(pool: &PgPool, hash_id: &str) -> Result<()> {
+    sqlx::query("UPDATE media SET deleted_at = NULL WHERE hash_id = $1;")
+        .bind(hash_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
 /// Deletes a record.
-pub async fn remove_media_record(pool: &PgPool, hash_id: &str) -> Result<()> {
+pub async fn remove_media_record(conn: &mut PgConnection, hash_id: &str) -> Result<()> {
     sqlx::query("DELETE FROM media WHERE hash_id = $1;")
         .bind(hash_id)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Fetches all webhooks.
+pub async fn fetch_webhooks(pool: &PgPool) -> Result<Vec<Webhook>> {
+    let webhooks = sqlx::query_as("SELECT * FROM webhooks ORDER BY id;").fetch_all(pool).await?;
+
+    Ok(webhooks)
+}
+
+/// Fetches a webhook.
+pub async fn fetch_webhook(pool: &PgPool, id: i64) -> Result<Option<Webhook>> {
+    let webhook = sqlx::query_as("SELECT * FROM webhooks WHERE id = $1;")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(webhook)
+}
+
+/// Registers a webhook.
+pub async fn insert_webhook(pool: &PgPool, url: &str, secret: &str, events: &[String]) -> Result<Webhook> {
+    let webhook = sqlx::query_as("INSERT INTO webhooks (url, secret, events) VALUES ($1, $2, $3) RETURNING *;")
+        .bind(url)
+        .bind(secret)
+        .bind(events)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(webhook)
+}
+
+/// Enables or disables a webhook.
+pub async fn update_webhook_enabled(pool: &PgPool, id: i64, enabled: bool) -> Result<()> {
+    sqlx::query("UPDATE webhooks SET is_enabled = $1 WHERE id = $2;")
+        .bind(enabled)
+        .bind(id)
         .execute(pool)
         .await?;
 
     Ok(())
 }
 
+/// Deletes a webhook and its delivery history.
+pub async fn remove_webhook(pool: &PgPool, id: i64) -> Result<()> {
+    sqlx::query("DELETE FROM webhooks WHERE id = $1;").bind(id).execute(pool).await?;
+
+    Ok(())
+}
+
+/// Queues deliveries of an event for all enabled webhooks subscribing it.
+/// Returns the number of queued deliveries.
+pub async fn insert_webhook_deliveries(pool: &PgPool, event: &str, payload: &str) -> Result<u64> {
+    let result = sqlx::query(
+        r#"
+        INSERT INTO webhook_deliveries (webhook_id, event, payload)
+        SELECT id, $1, $2 FROM webhooks WHERE is_enabled = TRUE AND $1 = ANY(events);
+        "#,
+    )
+    .bind(event)
+    .bind(payload)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Fetches pending deliveries whose next attempt is due.
+pub async fn fetch_due_webhook_deliveries(pool: &PgPool, limit: usize) -> Result<Vec<WebhookDelivery>> {
+    let deliveries = sqlx::query_as(
+        r#"
+        SELECT * FROM webhook_deliveries
+        WHERE status = 'pending' AND next_attempt_at <= $1
+        ORDER BY next_attempt_at LIMIT $2;
+        "#,
+    )
+    .bind(OffsetDateTime::now_local()?)
+    .bind(limit as i64)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(deliveries)
+}
+
+/// Fetches recent deliveries for the history.
+pub async fn fetch_recent_webhook_deliveries(pool: &PgPool, limit: usize) -> Result<Vec<WebhookDelivery>> {
+    let deliveries = sqlx::query_as("SELECT * FROM webhook_deliveries ORDER BY created_at DESC, id DESC LIMIT $1;")
+        .bind(limit as i64)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(deliveries)
+}
+
+/// Records the result of a delivery attempt.
+pub async fn update_webhook_delivery(pool: &PgPool, delivery: &WebhookDelivery) -> Result<()> {
+    sqlx::query(
+        r#"
+        UPDATE webhook_deliveries
+        SET status = $1, attempts = $2, next_attempt_at = $3, response_status = $4, last_error = $5, delivered_at = $6
+        WHERE id = $7;
+        "#,
+    )
+    .bind(&delivery.status)
+    .bind(delivery.attempts)
+    .bind(delivery.next_attempt_at)
+    .bind(delivery.response_status)
+    .bind(&delivery.last_error)
+    .bind(delivery.delivered_at)
+    .bind(delivery.id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
 /// Judges whether given `DatabaseError` implies constraint errors.
 fn is_conflicting(sql_err: &dyn DatabaseError) -> bool {
     // On Postgres (and MySQL), SQLSTATE 23___ represents constraint error
@@ -0,0 +1,320 @@
+//! Contains hash ID generation.
+
+use crate::application::HashIdStrategy;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{ensure, Result};
+use rand::prelude::*;
+
+/// Alphabet of ULID (Crockford's Base32, lowercased).
+const ULID_ALPHABET: &[u8] = b"0123456789abcdefghjkmnpqrstvwxyz";
+
+/// Length of random part of time-sortable IDs.
+const SORTABLE_RANDOM_LENGTH: usize = 4;
+
+/// Generates hash IDs for new media.
+/// Existing IDs are parsed only to restore the sequence, so changing the strategy keeps them working.
+#[derive(Debug, Clone)]
+pub struct HashIdGenerator {
+    strategy: HashIdStrategy,
+    alphabet: Vec<u8>,
+    min_length: usize,
+    max_attempts: usize,
+}
+
+impl HashIdGenerator {
+    /// Constructs new generator.
+    /// The alphabet may contain only ASCII alphanumerics, `-` and `_`, without duplicates.
+    pub fn new(strategy: HashIdStrategy, alphabet: &str, min_length: usize, max_attempts: usize) -> Result<HashIdGenerator> {
+        let mut alphabet = alphabet.as_bytes().to_vec();
+        ensure!(
+            alphabet.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'-' || *c == b'_'),
+            "Hash ID alphabet may contain only ASCII alphanumerics, '-' and '_'"
+        );
+        ensure!(alphabet.len() >= 3, "Hash ID alphabet must have at least 3 characters");
+        let mut sorted = alphabet.clone();
+        sorted.sort_unstable();
+        sorted.dedup();
+        ensure!(
+            sorted.len() == alphabet.len(),
+            "Hash ID alphabet must not have duplicate characters"
+        );
+        ensure!((1..=64).contains(&min_length), "Hash ID length must be between 1 and 64");
+        ensure!(max_attempts > 0, "Hash ID attempts must be positive");
+
+        // Time-sortable IDs are sorted by byte order of the alphabet
+        if strategy == HashIdStrategy::Sortable {
+            alphabet = sorted;
+        }
+
+        Ok(HashIdGenerator {
+            strategy,
+            alphabet,
+            min_length,
+            max_attempts,
+        })
+    }
+
+    /// Maximum number of attempts on conflict.
+    pub fn max_attempts(&self) -> usize {
+        self.max_attempts
+    }
+
+    /// Whether `generate` requires a sequence number.
+    pub fn needs_sequence(&self) -> bool {
+        self.strategy == HashIdStrategy::Sqids
+    }
+
+    /// Generates an ID.
+    /// `attempt` counts from 0 and lengthens random IDs on conflict.
+    /// `sequence` must be given if `needs_sequence` is true.
+    pub fn generate(&self, attempt: usize, sequence: Option<u64>) -> String {
+        match self.strategy {
+            HashIdStrategy::Random => self.random_chars(self.min_length + attempt),
+            HashIdStrategy::Sqids => self.encode_sequence(sequence.expect("Sequence number must be given")),
+            HashIdStrategy::Ulid => generate_ulid(),
+            HashIdStrategy::Sortable => self.sortable(attempt),
+        }
+    }
+
+    /// Picks characters with repetition.
+    fn random_chars(&self, length: usize) -> String {
+        let mut rng = thread_rng();
+        (0..length)
+            .map(|_| *self.alphabet.choose(&mut rng).expect("Alphabet should not be empty") as char)
+            .collect()
+    }
+
+    /// Encodes the sequence number like Sqids.
+    /// The first character is chosen by the number and rotates the alphabet for the rest,
+    /// so consecutive numbers don't look consecutive.
+    /// Distinct numbers always produce distinct IDs.
+    fn encode_sequence(&self, number: u64) -> String {
+        let base = self.alphabet.len() as u64;
+        let offset = (number % base) as usize;
+        let mut rotated = self.alphabet.clone();
+        rotated.rotate_left(offset);
+
+        let mut digits = vec![];
+        let mut rest = number;
+        loop {
+            digits.push(rotated[(rest % base) as usize]);
+            rest /= base;
+            if rest == 0 {
+                break;
+            }
+        }
+        while digits.len() + 1 < self.min_length {
+            digits.push(rotated[0]);
+        }
+        digits.reverse();
+
+        let mut encoded = String::with_capacity(digits.len() + 1);
+        encoded.push(self.alphabet[offset] as char);
+        encoded.extend(digits.into_iter().map(|c| c as char));
+        encoded
+    }
+
+    /// Decodes the sequence number from an ID encoded by `encode_sequence` with the same alphabet.
+    /// Returns `None` for IDs of other strategies or alphabets, except ones that happen to have the same shape.
+    pub fn decode_sequence(&self, id: &str) -> Option<u64> {
+        let base = self.alphabet.len() as u64;
+        let (&first, digits) = id.as_bytes().split_first()?;
+        let offset = self.alphabet.iter().position(|&c| c == first)?;
+        let mut rotated = self.alphabet.clone();
+        rotated.rotate_left(offset);
+
+        let mut number: u64 = 0;
+        for digit in digits {
+            let value = rotated.iter().position(|c| c == digit)? as u64;
+            number = number.checked_mul(base)?.checked_add(value)?;
+        }
+        if self.encode_sequence(number) == id {
+            Some(number)
+        } else {
+            None
+        }
+    }
+
+    /// Generates fixed-width timestamp in milliseconds followed by random characters.
+    fn sortable(&self, attempt: usize) -> String {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        self.sortable_at(millis, attempt)
+    }
+
+    /// Generates a time-sortable ID for the timestamp.
+    fn sortable_at(&self, millis: u64, attempt: usize) -> String {
+        let base = self.alphabet.len() as u64;
+
+        // Enough width for 48-bit timestamps
+        let mut width = 1;
+        let mut capacity = base;
+        while capacity < (1 << 48) {
+            capacity = capacity.saturating_mul(base);
+            width += 1;
+        }
+
+        let mut timestamp = vec![self.alphabet[0]; width];
+        let mut rest = millis;
+        for digit in timestamp.iter_mut().rev() {
+            *digit = self.alphabet[(rest % base) as usize];
+            rest /= base;
+        }
+
+        let random_length = self.min_length.saturating_sub(width).max(SORTABLE_RANDOM_LENGTH) + attempt;
+        let mut encoded: String = timestamp.into_iter().map(|c| c as char).collect();
+        encoded.push_str(&self.random_chars(random_length));
+        encoded
+    }
+}
+
+/// Generates a ULID in lowercase.
+fn generate_ulid() -> String {
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+    encode_ulid(millis, thread_rng().gen())
+}
+
+/// Encodes 48-bit timestamp and 80-bit randomness into a ULID.
+fn encode_ulid(millis: u128, randomness: u128) -> String {
+    let value = ((millis & ((1 << 48) - 1)) << 80) | (randomness & ((1 << 80) - 1));
+
+    (0..26)
+        .rev()
+        .map(|i| ULID_ALPHABET[((value >> (i * 5)) & 0x1f) as usize] as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    const ALPHABET: &str = "0123456789abcdefghijklmnopqrstuvwxyz";
+
+    fn build(strategy: HashIdStrategy, alphabet: &str, min_length: usize) -> HashIdGenerator {
+        HashIdGenerator::new(strategy, alphabet, min_length, 10).expect("Valid configuration")
+    }
+
+    #[test]
+    fn rejects_invalid_alphabets() {
+        assert!(HashIdGenerator::new(HashIdStrategy::Random, "ab", 6, 10).is_err());
+        assert!(HashIdGenerator::new(HashIdStrategy::Random, "abca", 6, 10).is_err());
+        assert!(HashIdGenerator::new(HashIdStrategy::Random, "abc/", 6, 10).is_err());
+        assert!(HashIdGenerator::new(HashIdStrategy::Random, ALPHABET, 0, 10).is_err());
+        assert!(HashIdGenerator::new(HashIdStrategy::Random, ALPHABET, 6, 0).is_err());
+    }
+
+    #[test]
+    fn random_ids_lengthen_on_attempts() {
+        let generator = build(HashIdStrategy::Random, ALPHABET, 6);
+        for attempt in 0..3 {
+            let id = generator.generate(attempt, None);
+            assert_eq!(id.len(), 6 + attempt);
+            assert!(id.bytes().all(|c| ALPHABET.as_bytes().contains(&c)));
+        }
+    }
+
+    #[test]
+    fn sqids_ids_are_unique_across_sequence() {
+        let generator = build(HashIdStrategy::Sqids, ALPHABET, 6);
+        let ids: HashSet<_> = (0..100_000).map(|n| generator.generate(0, Some(n))).collect();
+        assert_eq!(ids.len(), 100_000);
+    }
+
+    #[test]
+    fn sqids_ids_have_fixed_width() {
+        let generator = build(HashIdStrategy::Sqids, ALPHABET, 6);
+        let capacity = 36u64.pow(5);
+        for n in [0, 1, 35, 36, 1295, 1296, capacity - 1] {
+            assert_eq!(generator.generate(0, Some(n)).len(), 6, "sequence {}", n);
+        }
+        // Widened only after running out of the minimum length
+        assert_eq!(generator.generate(0, Some(capacity)).len(), 7);
+    }
+
+    #[test]
+    fn sqids_consecutive_ids_look_different() {
+        let generator = build(HashIdStrategy::Sqids, ALPHABET, 6);
+        for n in 0..1000 {
+            let (lhs, rhs) = (generator.generate(0, Some(n)), generator.generate(0, Some(n + 1)));
+            assert_ne!(lhs[..1], rhs[..1], "sequence {}", n);
+        }
+    }
+
+    #[test]
+    fn sqids_ids_decode_into_sequence() {
+        let generator = build(HashIdStrategy::Sqids, ALPHABET, 6);
+        for n in [0, 1, 35, 36, 1295, 36u64.pow(5), u64::MAX] {
+            assert_eq!(generator.decode_sequence(&generator.generate(0, Some(n))), Some(n));
+        }
+        assert_eq!(generator.decode_sequence(""), None);
+        assert_eq!(generator.decode_sequence("abc/de"), None);
+        // The first character must match the number
+        let id = generator.generate(0, Some(1));
+        assert_eq!(generator.decode_sequence(&format!("0{}", &id[1..])), None);
+    }
+
+    #[test]
+    fn restored_sequence_avoids_restored_ids() {
+        // Some sequence numbers are consumed by failed uploads, so the count of media is not enough
+        let generator = build(HashIdStrategy::Sqids, ALPHABET, 6);
+        let restored: HashSet<_> = (1..=200).filter(|n| n % 3 != 0).map(|n| generator.generate(0, Some(n))).collect();
+        let sequence = restored.iter().filter_map(|id| generator.decode_sequence(id)).max();
+        assert_eq!(sequence, Some(200));
+
+        // Uploads after restoring continue from the next number
+        for n in 201..1000 {
+            assert!(!restored.contains(&generator.generate(0, Some(n))));
+        }
+    }
+
+    #[test]
+    fn sortable_ids_are_ordered_by_time() {
+        // The alphabet is sorted by the constructor
+        let generator = build(HashIdStrategy::Sortable, "zyxwvutsrqponmlkjihgfedcba9876543210", 6);
+        let timestamps = [0, 1, 35, 36, 999, 1_000_000, 1_700_000_000_000, (1 << 48) - 1];
+        let ids: Vec<_> = timestamps.iter().map(|&t| generator.sortable_at(t, 0)).collect();
+        for pair in ids.windows(2) {
+            assert!(pair[0] < pair[1], "{} < {}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn sortable_ids_have_fixed_width() {
+        // 36^10 > 2^48 > 36^9
+        let generator = build(HashIdStrategy::Sortable, ALPHABET, 6);
+        for t in [0, 1_700_000_000_000, (1 << 48) - 1] {
+            assert_eq!(generator.sortable_at(t, 0).len(), 10 + SORTABLE_RANDOM_LENGTH);
+            assert_eq!(generator.sortable_at(t, 2).len(), 10 + SORTABLE_RANDOM_LENGTH + 2);
+        }
+
+        let generator = build(HashIdStrategy::Sortable, ALPHABET, 16);
+        assert_eq!(generator.sortable_at(0, 0).len(), 16);
+    }
+
+    #[test]
+    fn ulids_have_ulid_shape() {
+        let generator = build(HashIdStrategy::Ulid, ALPHABET, 6);
+        let ids: HashSet<_> = (0..10_000).map(|_| generator.generate(0, None)).collect();
+        assert_eq!(ids.len(), 10_000);
+        for id in &ids {
+            assert_eq!(id.len(), 26);
+            assert!(id.bytes().all(|c| ULID_ALPHABET.contains(&c)));
+            // 130 bits encode 128 bits, so the first character is at most 7
+            assert!(id.as_bytes()[0] <= b'7');
+        }
+    }
+
+    #[test]
+    fn ulids_are_ordered_by_time() {
+        assert_eq!(encode_ulid(0, 0), "00000000000000000000000000");
+        assert_eq!(encode_ulid((1 << 48) - 1, (1 << 80) - 1), "7zzzzzzzzzzzzzzzzzzzzzzzzz");
+        assert!(encode_ulid(1, (1 << 80) - 1) < encode_ulid(2, 0));
+        assert!(encode_ulid(1_700_000_000_000, 0) < encode_ulid(1_700_000_000_001, 0));
+    }
+}
@@ -1,15 +1,49 @@
 //! Contains media manipulations.
 
-use async_std::path::Path;
-use std::{fs::File as SyncFile, io::BufWriter as SyncBufWriter, path::Path as SyncPath};
+use crate::{
+    action::database::{
+        count_storage_references, fetch_media_by_content_hash, is_identifier_taken, lock_media_record, lock_storage_references,
+        remove_media_record, reserve_media_record, update_media_variants, SlugTakenError,
+    },
+    application::{DuplicatePolicy, ExifPolicy, State, ThumbnailMode},
+    entity::Media,
+};
+
+use async_std::{
+    fs,
+    path::{Path, PathBuf},
+    prelude::*,
+    task::spawn,
+};
+use std::{
+    cmp::Ordering,
+    fs::{self as sync_fs, File as SyncFile},
+    io::{BufWriter as SyncBufWriter, Cursor, ErrorKind},
+    path::{Path as SyncPath, PathBuf as SyncPathBuf},
+    str,
+    time::{Duration, SystemTime},
+};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, ensure, Result};
+use data_encoding::HEXLOWER;
+use exif::{DateTime as ExifDateTime, Exif, In, Reader as ExifReader, Tag, Value as ExifValue};
 use image::{
-    codecs::{gif::GifEncoder, jpeg::JpegEncoder, png::PngEncoder},
-    imageops::FilterType,
-    DynamicImage, GenericImageView, ImageEncoder, ImageFormat,
+    codecs::{
+        avif::AvifEncoder,
+        gif::GifEncoder,
+        jpeg::JpegEncoder,
+        png::PngEncoder,
+        webp::{WebPEncoder, WebPQuality},
+    },
+    imageops::{self, FilterType},
+    ColorType, DynamicImage, GenericImageView, GrayImage, ImageEncoder, ImageFormat, Rgba, RgbaImage,
 };
+use log::warn;
 use mime_guess::MimeGuess;
+use rand::{distributions::Alphanumeric, prelude::*};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, Time};
 
 const ALLOWED_TYPES: &[(&str, ImageFormat)] = &[
     ("image/png", ImageFormat::Png),
@@ -17,18 +51,97 @@ const ALLOWED_TYPES: &[(&str, ImageFormat)] = &[
     ("image/gif", ImageFormat::Gif),
 ];
 
-const THUMBNAIL_WIDTH: u32 = 320;
-const THUMBNAIL_HEIGHT: u32 = 180;
+const ENTROPY_CROP_STEPS: u32 = 16;
+const ENTROPY_SAMPLE_SIZE: u32 = 256;
+const PLACEHOLDER_SAMPLE_SIZE: u32 = 64;
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+
+/// Maximum length of EXIF texts stored in `exif_camera` and `exif_lens`.
+const EXIF_TEXT_LENGTH: usize = 256;
+pub const STAGING_DIRECTORY: &str = ".staging";
+
+/// Staged files older than this are leftovers of uploads interrupted by a crash.
+pub const STAGING_LEFTOVER_AGE: Duration = Duration::from_secs(3600);
+
+/// Slugs which cannot be used because they collide with routes.
+const RESERVED_SLUGS: &[&str] = &[
+    "api",
+    "index",
+    "m",
+    "media",
+    "new",
+    "signin",
+    "signout",
+    "static",
+    "thumbnails",
+    "trash",
+    "upload",
+];
+const SLUG_LENGTH: (usize, usize) = (3, 64);
+
+/// Formats of derivatives served by content negotiation, in order of preference.
+pub const VARIANT_FORMATS: &[(&str, &str)] = &[("avif", "image/avif"), ("webp", "image/webp")];
+const WEBP_QUALITY: u8 = 80;
+const AVIF_SPEED: u8 = 8;
+const AVIF_QUALITY: u8 = 70;
 
 #[derive(Debug)]
 pub struct ValidatedImage {
     pub image: DynamicImage,
     pub format: ImageFormat,
+    /// Size of the uploaded data, replaced with the size of the stored original when it is written.
     pub filesize: usize,
+    pub content_hash: String,
+    pub perceptual_hash: i64,
+    pub exif: Option<ExifMetadata>,
+    pub blurhash: Option<String>,
+    pub dominant_color: String,
+}
+
+impl ValidatedImage {
+    /// Discards recorded EXIF metadata.
+    pub fn strip_exif(&mut self) {
+        self.exif = None;
+    }
+}
+
+/// Options given at upload.
+#[derive(Debug, Clone, Default)]
+pub struct UploadOptions {
+    pub private: bool,
+    pub strip_exif: bool,
+    pub expires_at: Option<OffsetDateTime>,
+    pub max_views: Option<i32>,
+    pub comment: Option<String>,
+    pub slug: Option<String>,
+
+    /// Overrides the upload date, used by importing.
+    pub uploaded_at: Option<OffsetDateTime>,
+}
+
+/// Result of `store_media`.
+#[derive(Debug)]
+pub enum StoredMedia {
+    /// A new record was created.
+    Created(Media),
+
+    /// The same image has already been uploaded and no record was created.
+    Existing(Media),
+}
+
+/// Non-personal EXIF metadata.
+/// GPS location and personal tags (owner name, serial numbers, etc.) are never read,
+/// and served images are always re-encoded without any metadata.
+#[derive(Debug, Clone, Default)]
+pub struct ExifMetadata {
+    pub camera: Option<String>,
+    pub lens: Option<String>,
+    pub captured_at: Option<PrimitiveDateTime>,
+    pub orientation: Option<u16>,
 }
 
 /// Validates input filename and blob.
-/// Returns decoded image and extension if succeeded.
+/// Returns decoded image, rotated upright by EXIF orientation, and extension if succeeded.
 pub fn validate_image_file(filename: impl AsRef<Path>, data: &[u8]) -> Result<ValidatedImage> {
     let path = filename.as_ref();
     let extension = match path.extension().and_then(|ext| ext.to_str()) {
@@ -50,40 +163,293 @@ pub fn validate_image_file(filename: impl AsRef<Path>, data: &[u8]) -> Result<Va
         Ok(f) if f == detected_type => f,
         _ => bail!("Unsupported image type"),
     };
+    let exif = read_exif_metadata(data);
     let image = image::load_from_memory_with_format(data, format)?;
+    let image = match exif.as_ref().and_then(|e| e.orientation) {
+        Some(orientation) => apply_orientation(image, orientation),
+        None => image,
+    };
 
     Ok(ValidatedImage {
-        image,
         format,
         filesize: data.len(),
+        content_hash: HEXLOWER.encode(&Sha256::digest(data)),
+        perceptual_hash: compute_perceptual_hash(&image),
+        exif,
+        blurhash: compute_blurhash(&image),
+        dominant_color: compute_dominant_color(&image),
+        image,
     })
 }
 
-/// Creates thumbnail image.
-/// If original image is small enough, return `None`.
-pub fn create_thumbnail(original_image: &DynamicImage) -> Option<DynamicImage> {
-    let (width, height) = original_image.dimensions();
+/// Computes BlurHash string of the image from downscaled pixels.
+pub fn compute_blurhash(image: &DynamicImage) -> Option<String> {
+    let sample = image.thumbnail(PLACEHOLDER_SAMPLE_SIZE, PLACEHOLDER_SAMPLE_SIZE).into_rgba8();
+    let (width, height) = sample.dimensions();
+    blurhash::encode(BLURHASH_COMPONENTS.0, BLURHASH_COMPONENTS.1, width, height, sample.as_raw()).ok()
+}
+
+/// Computes dominant color of the image in `#rrggbb` form.
+/// Pixels are bucketed by the upper 4 bits of each channel, and the most frequent bucket is averaged.
+pub fn compute_dominant_color(image: &DynamicImage) -> String {
+    let sample = image.thumbnail(PLACEHOLDER_SAMPLE_SIZE, PLACEHOLDER_SAMPLE_SIZE).into_rgb8();
+
+    let mut buckets = vec![(0usize, [0u64; 3]); 4096];
+    for pixel in sample.pixels() {
+        let [r, g, b] = pixel.0;
+        let index = ((r as usize >> 4) << 8) | ((g as usize >> 4) << 4) | (b as usize >> 4);
+        let bucket = &mut buckets[index];
+        bucket.0 += 1;
+        bucket.1[0] += r as u64;
+        bucket.1[1] += g as u64;
+        bucket.1[2] += b as u64;
+    }
+
+    match buckets.iter().max_by_key(|(count, _)| *count) {
+        Some((count, sum)) if *count > 0 => {
+            let count = *count as u64;
+            format!("#{:02x}{:02x}{:02x}", sum[0] / count, sum[1] / count, sum[2] / count)
+        }
+        _ => "#000000".to_string(),
+    }
+}
+
+/// Reads non-personal EXIF metadata from image data.
+/// Returns `None` if the image has no EXIF.
+pub fn read_exif_metadata(data: &[u8]) -> Option<ExifMetadata> {
+    let exif = ExifReader::new().read_from_container(&mut Cursor::new(data)).ok()?;
+
+    let make = read_exif_ascii(&exif, Tag::Make);
+    let model = read_exif_ascii(&exif, Tag::Model);
+    let camera = match (make, model) {
+        (Some(make), Some(model)) if model.starts_with(&make) => Some(model),
+        (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+        (make, model) => make.or(model),
+    };
+    let camera = camera.map(|c| truncate_chars(c, EXIF_TEXT_LENGTH));
+    let lens = read_exif_ascii(&exif, Tag::LensModel).map(|l| truncate_chars(l, EXIF_TEXT_LENGTH));
+    let captured_at = exif
+        .get_field(Tag::DateTimeOriginal, In::PRIMARY)
+        .and_then(|f| match &f.value {
+            ExifValue::Ascii(values) => values.first().and_then(|v| ExifDateTime::from_ascii(v).ok()),
+            _ => None,
+        })
+        .and_then(|dt| {
+            let date = Date::from_calendar_date(dt.year as i32, Month::try_from(dt.month).ok()?, dt.day).ok()?;
+            let time = Time::from_hms(dt.hour, dt.minute, dt.second).ok()?;
+            Some(PrimitiveDateTime::new(date, time))
+        });
+    let orientation = exif
+        .get_field(Tag::Orientation, In::PRIMARY)
+        .and_then(|f| f.value.get_uint(0))
+        .and_then(|o| u16::try_from(o).ok());
+
+    Some(ExifMetadata {
+        camera,
+        lens,
+        captured_at,
+        orientation,
+    })
+}
+
+/// Rotates and flips the image according to EXIF orientation,
+/// so that derivatives and stored images are upright without the tag.
+pub fn apply_orientation(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
 
-    if width <= THUMBNAIL_WIDTH && height <= THUMBNAIL_HEIGHT {
+/// Reads an ASCII EXIF field as trimmed string.
+fn read_exif_ascii(exif: &Exif, tag: Tag) -> Option<String> {
+    let field = exif.get_field(tag, In::PRIMARY)?;
+    let value = match &field.value {
+        ExifValue::Ascii(values) => values.first()?,
+        _ => return None,
+    };
+    let text = str::from_utf8(value).ok()?.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+/// Truncates the text to at most `max_chars` characters, trimming whitespace left at the end.
+fn truncate_chars(text: String, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => text[..end].trim_end().to_string(),
+        None => text,
+    }
+}
+
+/// Computes perceptual hash (dHash) of the image.
+/// Each bit represents whether a pixel is brighter than its right neighbor in 9x8 grayscale.
+pub fn compute_perceptual_hash(image: &DynamicImage) -> i64 {
+    let gray = image.resize_exact(9, 8, FilterType::Triangle).into_luma8();
+
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = gray.get_pixel(x, y).0[0];
+            let right = gray.get_pixel(x + 1, y).0[0];
+            hash = (hash << 1) | (left > right) as u64;
+        }
+    }
+    hash as i64
+}
+
+/// Creates thumbnail image in the given mode and size.
+/// In `fit` mode, if original image is small enough, return `None`.
+/// Other modes always make a thumbnail of exactly the given size.
+pub fn create_thumbnail(original_image: &DynamicImage, mode: ThumbnailMode, size: (u32, u32), background: [u8; 3]) -> Option<DynamicImage> {
+    let (width, height) = original_image.dimensions();
+    let (thumbnail_width, thumbnail_height) = size;
+    if mode == ThumbnailMode::Fit && width <= thumbnail_width && height <= thumbnail_height {
         // Original size will fit in thumbnail size
         return None;
-    } else if width <= THUMBNAIL_WIDTH {
-        // Clip top and bottom
-        let top_half = (height - THUMBNAIL_HEIGHT) / 2;
-        let cropped = original_image.crop_imm(0, top_half, width, THUMBNAIL_HEIGHT);
-        Some(cropped)
-    } else if height <= THUMBNAIL_HEIGHT {
-        // Clip left and right
-        let left_half = (width - THUMBNAIL_WIDTH) / 2;
-        let cropped = original_image.crop_imm(left_half, 0, THUMBNAIL_WIDTH, height);
-        Some(cropped)
+    }
+
+    let thumbnail = match mode {
+        ThumbnailMode::Fit => original_image.resize(thumbnail_width, thumbnail_height, FilterType::Triangle),
+        ThumbnailMode::Fill => fill_center(original_image, thumbnail_width, thumbnail_height),
+        ThumbnailMode::Entropy => fill_by_entropy(original_image, thumbnail_width, thumbnail_height),
+        ThumbnailMode::Letterbox => letterbox(original_image, thumbnail_width, thumbnail_height, background),
+    };
+    Some(thumbnail)
+}
+
+/// Calculates the size of the thumbnail `create_thumbnail` makes for the original size.
+pub fn thumbnail_dimensions(width: u32, height: u32, mode: ThumbnailMode, size: (u32, u32)) -> (u32, u32) {
+    let (thumbnail_width, thumbnail_height) = size;
+    match mode {
+        ThumbnailMode::Fit if width <= thumbnail_width && height <= thumbnail_height => (width, height),
+        ThumbnailMode::Fit => {
+            let scale = f64::min(thumbnail_width as f64 / width as f64, thumbnail_height as f64 / height as f64);
+            (
+                ((width as f64 * scale).round() as u32).max(1),
+                ((height as f64 * scale).round() as u32).max(1),
+            )
+        }
+        ThumbnailMode::Fill | ThumbnailMode::Entropy | ThumbnailMode::Letterbox => size,
+    }
+}
+
+/// Calculates the largest size in the image which has the same aspect ratio as the box.
+fn cover_size(width: u32, height: u32, box_width: u32, box_height: u32) -> (u32, u32) {
+    if width as u64 * box_height as u64 > height as u64 * box_width as u64 {
+        let cropped_width = (height as u64 * box_width as u64 / box_height as u64) as u32;
+        (cropped_width.clamp(1, width), height)
     } else {
-        // Scale down
-        let scaled = original_image.resize(THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT, FilterType::Triangle);
-        Some(scaled)
+        let cropped_height = (width as u64 * box_height as u64 / box_width as u64) as u32;
+        (width, cropped_height.clamp(1, height))
     }
 }
 
+/// Crops the center region of the box's aspect ratio, and scales it to the box.
+/// Cropping comes first so that very tall or wide images are never scaled up as a whole.
+fn fill_center(image: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let (original_width, original_height) = image.dimensions();
+    let (crop_width, crop_height) = cover_size(original_width, original_height, width, height);
+    let x = (original_width - crop_width) / 2;
+    let y = (original_height - crop_height) / 2;
+    image
+        .crop_imm(x, y, crop_width, crop_height)
+        .resize_exact(width, height, FilterType::Triangle)
+}
+
+/// Crops the region of the box's aspect ratio with the highest entropy, and scales it to the box.
+/// The region is searched on a downscaled copy, so the cost doesn't depend on the original size.
+fn fill_by_entropy(image: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let (original_width, original_height) = image.dimensions();
+    let (crop_width, crop_height) = cover_size(original_width, original_height, width, height);
+
+    let scale = f64::min(
+        1.0,
+        f64::min(
+            ENTROPY_SAMPLE_SIZE as f64 / original_width as f64,
+            ENTROPY_SAMPLE_SIZE as f64 / original_height as f64,
+        ),
+    );
+    let sample_width = ((original_width as f64 * scale).round() as u32).max(1);
+    let sample_height = ((original_height as f64 * scale).round() as u32).max(1);
+    let gray = image.thumbnail_exact(sample_width, sample_height).into_luma8();
+    let (window_width, window_height) = cover_size(sample_width, sample_height, width, height);
+
+    // Only one axis overflows, so slide the window along it
+    let (overflow_x, overflow_y) = (sample_width - window_width, sample_height - window_height);
+    let overflow = overflow_x.max(overflow_y);
+    let step = (overflow / ENTROPY_CROP_STEPS).max(1);
+    let best_offset = (0..=overflow)
+        .step_by(step as usize)
+        .map(|offset| {
+            let (x, y) = if overflow_x > 0 { (offset, 0) } else { (0, offset) };
+            (offset, region_entropy(&gray, x, y, window_width, window_height))
+        })
+        .max_by(|(_, lhs), (_, rhs)| lhs.partial_cmp(rhs).unwrap_or(Ordering::Equal))
+        .map(|(offset, _)| offset)
+        .unwrap_or(0);
+
+    // Maps the offset back onto the original
+    let (x, y) = if overflow_x > 0 {
+        let x = (best_offset as u64 * original_width as u64 / sample_width as u64) as u32;
+        (x.min(original_width - crop_width), 0)
+    } else {
+        let y = (best_offset as u64 * original_height as u64 / sample_height as u64) as u32;
+        (0, y.min(original_height - crop_height))
+    };
+    image
+        .crop_imm(x, y, crop_width, crop_height)
+        .resize_exact(width, height, FilterType::Triangle)
+}
+
+/// Calculates Shannon entropy of luminance in the region.
+fn region_entropy(gray: &GrayImage, x: u32, y: u32, width: u32, height: u32) -> f64 {
+    let mut histogram = [0usize; 256];
+    for py in y..(y + height) {
+        for px in x..(x + width) {
+            histogram[gray.get_pixel(px, py).0[0] as usize] += 1;
+        }
+    }
+
+    let total = (width as u64 * height as u64) as f64;
+    histogram
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Scales the image down to fit in the box, and pads it with the background color.
+/// Images smaller than the box are centered as is.
+fn letterbox(image: &DynamicImage, width: u32, height: u32, background: [u8; 3]) -> DynamicImage {
+    let (original_width, original_height) = image.dimensions();
+    let fitted = if original_width <= width && original_height <= height {
+        image.to_rgba8()
+    } else {
+        image.resize(width, height, FilterType::Triangle).into_rgba8()
+    };
+    let (fitted_width, fitted_height) = fitted.dimensions();
+
+    let [r, g, b] = background;
+    let mut canvas = RgbaImage::from_pixel(width, height, Rgba([r, g, b, 255]));
+    let x = (width - fitted_width) / 2;
+    let y = (height - fitted_height) / 2;
+    imageops::overlay(&mut canvas, &fitted, x as i64, y as i64);
+
+    DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).into_rgb8())
+}
+
 /// Saves image into file.
 pub fn save_image(image: &DynamicImage, format: ImageFormat, path: impl AsRef<SyncPath>) -> Result<()> {
     let (width, height) = image.dimensions();
@@ -107,3 +473,335 @@ pub fn save_image(image: &DynamicImage, format: ImageFormat, path: impl AsRef<Sy
     }
     Ok(())
 }
+
+/// Removes the original and the thumbnail files of media.
+/// Files already missing are ignored, so that a partially removed media can be removed again.
+pub async fn remove_media_files(media_root: impl AsRef<Path>, media: &Media) -> Result<()> {
+    let media_root = media_root.as_ref();
+
+    remove_file_if_exists(media_root.join(media.original_filename())).await?;
+    if media.has_thumbnail {
+        remove_file_if_exists(media_root.join(media.thumbnail_filename())).await?;
+    }
+    for variant in media.available_variants() {
+        remove_file_if_exists(media_root.join(media.variant_filename(variant))).await?;
+    }
+    Ok(())
+}
+
+/// Removes a file, treating an already missing file as removed.
+async fn remove_file_if_exists(path: impl AsRef<Path>) -> Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Encodes derivatives into `variants` directory and returns their extensions.
+/// Derivatives not smaller than the original are discarded.
+pub fn create_variants(image: &DynamicImage, original_size: u64, media_root: &SyncPath, storage_id: &str) -> Result<Vec<String>> {
+    let rgba = image.to_rgba8();
+    let (width, height) = rgba.dimensions();
+
+    let mut variants = vec![];
+    for (extension, _) in VARIANT_FORMATS {
+        let mut encoded = vec![];
+        match *extension {
+            "avif" => {
+                let encoder = AvifEncoder::new_with_speed_quality(&mut encoded, AVIF_SPEED, AVIF_QUALITY);
+                encoder.write_image(rgba.as_raw(), width, height, ColorType::Rgba8)?;
+            }
+            "webp" => {
+                let encoder = WebPEncoder::new_with_quality(&mut encoded, WebPQuality::lossy(WEBP_QUALITY));
+                encoder.encode(rgba.as_raw(), width, height, ColorType::Rgba8)?;
+            }
+            _ => unreachable!("Unknown variant format"),
+        }
+
+        let path = media_root.join(format!("variants/{}.{}", storage_id, extension));
+        if (encoded.len() as u64) < original_size {
+            sync_fs::write(path, &encoded)?;
+            variants.push(extension.to_string());
+        } else if path.exists() {
+            sync_fs::remove_file(path)?;
+        }
+    }
+    Ok(variants)
+}
+
+/// Generates derivatives of stored files of the media and records them.
+/// Animated GIFs have no derivatives since they would lose animation.
+/// Previous derivatives which are no longer generated are removed.
+pub async fn generate_variants(pool: &PgPool, media_root: impl AsRef<Path>, media: &Media) -> Result<Vec<String>> {
+    let media_root = media_root.as_ref();
+    let format = ImageFormat::from_extension(&media.extension);
+    let variants = match format {
+        Some(format @ (ImageFormat::Png | ImageFormat::Jpeg)) => {
+            let data = fs::read(media_root.join(media.original_filename())).await?;
+            fs::create_dir_all(media_root.join("variants")).await?;
+
+            let sync_root: SyncPathBuf = media_root.to_path_buf().into();
+            let storage_id = media.storage_id.clone();
+            spawn(async move {
+                let image = image::load_from_memory_with_format(&data, format)?;
+                create_variants(&image, data.len() as u64, &sync_root, &storage_id)
+            })
+            .await?
+        }
+        _ => vec![],
+    };
+
+    update_media_variants(pool, &media.storage_id, &variants).await?;
+    for stale in media.available_variants().iter().filter(|v| !variants.contains(v)) {
+        remove_file_if_exists(media_root.join(media.variant_filename(stale))).await?;
+    }
+    Ok(variants)
+}
+
+/// Deletes a media record permanently.
+/// Stored files are removed only when no other media refers to them.
+/// Records sharing the files stay locked until the deletion is committed, so that uploads can't link to removed files.
+pub async fn purge_media(pool: &PgPool, media_root: impl AsRef<Path>, media: &Media) -> Result<()> {
+    let mut transaction = pool.begin().await?;
+    lock_storage_references(&mut transaction, &media.storage_id).await?;
+    remove_media_record(&mut transaction, &media.hash_id).await?;
+    let references = count_storage_references(&mut transaction, &media.storage_id).await?;
+    transaction.commit().await?;
+
+    if references == 0 {
+        remove_media_files(media_root, media).await?;
+    }
+    Ok(())
+}
+
+/// Validates a slug and checks that no other media uses it.
+/// Slugs consist of lowercase alphanumerics and inner hyphens.
+/// This only rejects early; writing the record checks again in its transaction and fails with `SlugTakenError`.
+pub async fn check_slug(pool: &PgPool, slug: &str, owner: Option<&str>) -> Result<()> {
+    let (min_length, max_length) = SLUG_LENGTH;
+    ensure!(
+        (min_length..=max_length).contains(&slug.len()),
+        "Slug must be {} to {} characters",
+        min_length,
+        max_length
+    );
+    ensure!(
+        slug.bytes().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'-') && !slug.starts_with('-') && !slug.ends_with('-'),
+        "Slug may contain only lowercase letters, digits and inner hyphens"
+    );
+    ensure!(!RESERVED_SLUGS.contains(&slug), "Slug \"{}\" is reserved", slug);
+
+    let mut conn = pool.acquire().await?;
+    if is_identifier_taken(&mut conn, slug, owner).await? {
+        return Err(SlugTakenError(slug.to_string()).into());
+    }
+    Ok(())
+}
+
+/// Lists files in the staging directory which have not been modified for `older_than`.
+pub async fn list_staging_leftovers(media_root: impl AsRef<Path>, older_than: Duration) -> Result<Vec<PathBuf>> {
+    let directory = media_root.as_ref().join(STAGING_DIRECTORY);
+    if !directory.is_dir().await {
+        return Ok(vec![]);
+    }
+
+    let now = SystemTime::now();
+    let mut leftovers = vec![];
+    let mut entries = fs::read_dir(&directory).await?;
+    while let Some(entry) = entries.next().await {
+        let entry = entry?;
+        let metadata = entry.metadata().await?;
+        if !metadata.is_file() {
+            continue;
+        }
+        if now.duration_since(metadata.modified()?).unwrap_or_default() >= older_than {
+            leftovers.push(entry.path());
+        }
+    }
+    Ok(leftovers)
+}
+
+/// Stores an uploaded image, applying EXIF and duplicate policies.
+/// Files are written to the staging directory first and moved into place inside the database transaction,
+/// so a failed upload leaves neither a record nor files behind.
+pub async fn store_media(state: &State, mut validated_image: ValidatedImage, options: &UploadOptions) -> Result<StoredMedia> {
+    if options.strip_exif || state.exif_policy == ExifPolicy::Strip {
+        validated_image.strip_exif();
+    }
+    let uploaded_size = validated_image.filesize as u64;
+
+    let duplicate = match state.duplicate_policy {
+        DuplicatePolicy::Copy => None,
+        _ => {
+            let now = OffsetDateTime::now_local()?;
+            fetch_media_by_content_hash(&state.pool, &validated_image.content_hash)
+                .await?
+                .filter(|m| !m.is_expired(now))
+        }
+    };
+
+    if let Some(existing) = duplicate {
+        if state.duplicate_policy == DuplicatePolicy::Existing {
+            return Ok(StoredMedia::Existing(existing));
+        }
+
+        // The linked media is locked so that purging can't remove the shared files before this record is committed
+        let mut transaction = state.pool.begin().await?;
+        if lock_media_record(&mut transaction, &existing.hash_id).await? {
+            validated_image.filesize = existing.filesize as usize;
+            let record = reserve_media_record(
+                &mut transaction,
+                &validated_image,
+                existing.has_thumbnail,
+                options,
+                &state.hash_id_generator,
+                Some(&existing.storage_id),
+            )
+            .await?;
+            transaction.commit().await?;
+            state.metrics.record_upload(&record.extension, uploaded_size);
+            return Ok(StoredMedia::Created(record));
+        }
+        // The media has been purged meanwhile, so the files are stored anew
+    }
+
+    let mut written_files = vec![];
+    let result = store_new_media(state, validated_image, options, &mut written_files).await;
+    if result.is_err() {
+        for path in written_files {
+            if !path.exists().await {
+                continue;
+            }
+            if let Err(e) = fs::remove_file(&path).await {
+                warn!("Failed to clean up {}: {}", path.display(), e);
+            }
+        }
+    }
+    let record = result?;
+    state.metrics.record_upload(&record.extension, uploaded_size);
+    state.metrics.record_stored(record.filesize as u64, record.has_thumbnail);
+    Ok(StoredMedia::Created(record))
+}
+
+/// Writes files of new media and inserts its record.
+/// Every file that may be left is pushed to `written_files` so that the caller can remove them on failure.
+async fn store_new_media(
+    state: &State,
+    validated_image: ValidatedImage,
+    options: &UploadOptions,
+    written_files: &mut Vec<PathBuf>,
+) -> Result<Media> {
+    let staging_root = state.media_root.join(STAGING_DIRECTORY);
+    fs::create_dir_all(&staging_root).await?;
+
+    let staging_id: String = thread_rng().sample_iter(&Alphanumeric).take(16).map(char::from).collect();
+    let staged_original = staging_root.join(format!("{}.original", staging_id));
+    let staged_thumbnail = staging_root.join(format!("{}.thumbnail", staging_id));
+
+    let thumbnail = create_thumbnail(
+        &validated_image.image,
+        state.thumbnail_mode,
+        state.thumbnail_size,
+        state.thumbnail_background,
+    );
+    let has_thumbnail = thumbnail.is_some();
+
+    written_files.push(staged_original.clone());
+    let original_path = staged_original.clone();
+    let mut validated_image = spawn(async move {
+        save_image(&validated_image.image, validated_image.format, &original_path)?;
+        Ok::<_, anyhow::Error>(validated_image)
+    })
+    .await?;
+    // The original is re-encoded on saving, so the record holds the size of the stored file
+    validated_image.filesize = fs::metadata(&staged_original).await?.len() as usize;
+    if let Some(thumb) = thumbnail {
+        written_files.push(staged_thumbnail.clone());
+        let thumbnail_path = staged_thumbnail.clone();
+        spawn(async move { save_image(&thumb, ImageFormat::Jpeg, &thumbnail_path) }).await?;
+    }
+
+    let mut transaction = state.pool.begin().await?;
+    let record = reserve_media_record(
+        &mut transaction,
+        &validated_image,
+        has_thumbnail,
+        options,
+        &state.hash_id_generator,
+        None,
+    )
+    .await?;
+
+    let original_filename = state.media_root.join(record.original_filename());
+    fs::rename(&staged_original, &original_filename).await?;
+    written_files.push(original_filename);
+    if has_thumbnail {
+        let thumbnail_filename = state.media_root.join(record.thumbnail_filename());
+        fs::rename(&staged_thumbnail, &thumbnail_filename).await?;
+        written_files.push(thumbnail_filename);
+    }
+
+    transaction.commit().await?;
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use exif::{experimental::Writer as ExifWriter, Field};
+
+    fn tiff_with_ascii(fields: &[(Tag, &[u8])]) -> Vec<u8> {
+        let fields: Vec<_> = fields
+            .iter()
+            .map(|(tag, value)| Field {
+                tag: *tag,
+                ifd_num: In::PRIMARY,
+                value: ExifValue::Ascii(vec![value.to_vec()]),
+            })
+            .collect();
+        let mut writer = ExifWriter::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        let mut tiff = Cursor::new(vec![]);
+        writer.write(&mut tiff, false).expect("Writable EXIF");
+        tiff.into_inner()
+    }
+
+    #[test]
+    fn truncate_chars_keeps_char_boundaries() {
+        assert_eq!(truncate_chars("short".to_string(), 8), "short");
+        assert_eq!(truncate_chars("exactly8".to_string(), 8), "exactly8");
+        assert_eq!(truncate_chars("カメラのモデル名".to_string(), 3), "カメラ");
+        assert_eq!(truncate_chars("lens  name".to_string(), 6), "lens");
+    }
+
+    #[test]
+    fn exif_texts_fit_in_columns() {
+        let long_make = "M".repeat(200);
+        let long_model = "é".repeat(300);
+        let long_lens = " L".repeat(400);
+        let tiff = tiff_with_ascii(&[
+            (Tag::Make, long_make.as_bytes()),
+            (Tag::Model, long_model.as_bytes()),
+            (Tag::LensModel, long_lens.as_bytes()),
+        ]);
+
+        let metadata = read_exif_metadata(&tiff).expect("EXIF should be read");
+        let camera = metadata.camera.expect("Camera should be read");
+        let lens = metadata.lens.expect("Lens should be read");
+        assert_eq!(camera.chars().count(), EXIF_TEXT_LENGTH);
+        assert!(camera.starts_with(&long_make));
+        assert!(lens.chars().count() <= EXIF_TEXT_LENGTH);
+        assert!(lens.starts_with("L L"));
+    }
+
+    #[test]
+    fn exif_texts_are_trimmed() {
+        let tiff = tiff_with_ascii(&[(Tag::Make, b"  Maker \0"), (Tag::Model, b"Maker X100 ")]);
+        let metadata = read_exif_metadata(&tiff).expect("EXIF should be read");
+        assert_eq!(metadata.camera.as_deref(), Some("Maker X100"));
+        assert_eq!(metadata.lens, None);
+    }
+}
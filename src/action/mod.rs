@@ -1,5 +1,7 @@
 //! Contains Web-independent actions.
 
 pub(crate) mod database;
+pub(crate) mod hash_id;
 pub(crate) mod media;
 pub(crate) mod session;
+pub(crate) mod webhook;
@@ -13,10 +13,9 @@ use data_encoding::BASE64;
 use rand::prelude::*;
 use serde::{Deserialize, Serialize};
 use tide::sessions::Session;
-use time::OffsetDateTime;
+use time::{Duration, OffsetDateTime};
 use url::Url;
 
-const TOKEN_EXPIARY: i64 = 86400;
 const SESSION_ACCOUNT: &'static str = "kebisafe.account";
 const SESSION_FLASHES: &'static str = "kebisafe.flashes";
 
@@ -57,14 +56,24 @@ impl Common {
 
     /// Generates original media permalink.
     pub fn permalink_original(&self, media: &Media) -> String {
-        let relative = format!("/media/{}.{}", media.hash_id, media.extension);
+        let relative = format!("/media/{}.{}", media.public_id(), media.extension);
         self.hosted_at.join(&relative).map(|url| url.to_string()).unwrap_or_default()
     }
 
+    /// Generates permalink of the image embedded in the media page.
+    /// View-limited media embed only the thumbnail, so that opening the page (e.g. right after uploading) doesn't consume a view.
+    pub fn permalink_embedded(&self, media: &Media) -> Option<String> {
+        match (media.max_views, media.has_thumbnail) {
+            (None, _) => Some(self.permalink_original(media)),
+            (Some(_), true) => Some(self.permalink_thumbnail(media)),
+            (Some(_), false) => None,
+        }
+    }
+
     /// Generates thumbnail media permalink.
     pub fn permalink_thumbnail(&self, media: &Media) -> String {
         if media.has_thumbnail {
-            let relative = format!("/media/thumbnails/{}.{}", media.hash_id, "jpg");
+            let relative = format!("/media/thumbnails/{}.{}", media.public_id(), "jpg");
             self.hosted_at.join(&relative).map(|url| url.to_string()).unwrap_or_default()
         } else {
             self.permalink_original(media)
@@ -89,7 +98,7 @@ pub fn generate_csrf_token(cipher: &Aes256GcmSiv, session: &Session) -> Result<S
 }
 
 /// Verifies CSRF token.
-pub fn verify_csrf_token(cipher: &Aes256GcmSiv, session: &Session, token: &str) -> Result<()> {
+pub fn verify_csrf_token(cipher: &Aes256GcmSiv, session: &Session, token: &str, expiary: Duration) -> Result<()> {
     // Decode and decrypt token
     let decoded_buffer = BASE64.decode(token.as_bytes())?;
     ensure!(decoded_buffer.len() >= 12, "Not enough token length");
@@ -107,7 +116,7 @@ pub fn verify_csrf_token(cipher: &Aes256GcmSiv, session: &Session, token: &str)
 
     let now = OffsetDateTime::now_local()?.unix_timestamp();
     let token_time = params[1].parse().ok().unwrap_or(0);
-    ensure!(now - token_time <= TOKEN_EXPIARY, "Expired token");
+    ensure!(now - token_time <= expiary.whole_seconds(), "Expired token");
 
     Ok(())
 }
@@ -139,3 +148,51 @@ pub fn delete_account(session: &mut Session) -> Result<()> {
 pub fn get_account(session: &Session) -> Option<Account> {
     session.get(SESSION_ACCOUNT)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn common() -> Common {
+        Common {
+            hosted_at: Url::parse("https://example.com/").expect("Valid URL"),
+            account: Some(Account { name: "owner".to_string() }),
+            flashes: vec![],
+            csrf: String::new(),
+        }
+    }
+
+    #[test]
+    fn page_embeds_original_of_unlimited_media() {
+        let media = Media::fixture("abcdef");
+        assert_eq!(
+            common().permalink_embedded(&media).as_deref(),
+            Some("https://example.com/media/abcdef.png")
+        );
+    }
+
+    #[test]
+    fn view_limited_media_can_be_downloaded_after_upload_redirect() {
+        let mut media = Media::fixture("abcdef");
+        media.max_views = Some(1);
+        media.has_thumbnail = true;
+
+        // Uploading redirects to the media page, which embeds only the thumbnail that never consumes views
+        let embedded = common().permalink_embedded(&media);
+        assert_eq!(embedded.as_deref(), Some("https://example.com/media/thumbnails/abcdef.jpg"));
+        assert_ne!(embedded, Some(common().permalink_original(&media)));
+
+        // So the single view is left for the download
+        let now = OffsetDateTime::now_utc();
+        assert!(!media.is_expired(now));
+        media.view_count += 1;
+        assert!(media.is_expired(now));
+    }
+
+    #[test]
+    fn view_limited_media_without_thumbnail_embeds_nothing() {
+        let mut media = Media::fixture("abcdef");
+        media.max_views = Some(1);
+        assert_eq!(common().permalink_embedded(&media), None);
+    }
+}
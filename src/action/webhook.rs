@@ -0,0 +1,331 @@
+//! Contains webhook notifications.
+
+use crate::{
+    action::database::{insert_webhook_deliveries, update_webhook_delivery},
+    api::schema::ShowMediaResponse,
+    application::State,
+    entity::{Media, Webhook, WebhookDelivery},
+};
+
+use anyhow::Result;
+use data_encoding::HEXLOWER;
+use hmac::{Hmac, Mac};
+use log::{error, info};
+use rand::{distributions::Alphanumeric, prelude::*};
+use serde::Serialize;
+use sha2::Sha256;
+use surf::{http::mime, Client};
+use time::{format_description::well_known::Rfc3339, Duration, OffsetDateTime};
+
+/// Delay before the first retry, doubled on each failure.
+const RETRY_BASE_DELAY: Duration = Duration::seconds(30);
+
+/// Upper bound of retry delays.
+const RETRY_MAX_DELAY: Duration = Duration::hours(6);
+
+/// Length of generated secrets.
+const SECRET_LENGTH: usize = 32;
+
+pub const STATUS_SUCCEEDED: &str = "succeeded";
+pub const STATUS_FAILED: &str = "failed";
+
+/// Events notified to webhooks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebhookEvent {
+    /// A media has been uploaded
+    Uploaded,
+
+    /// Information of a media has been updated
+    Updated,
+
+    /// A media has been moved to the trash, or deleted permanently by purging or expiration
+    Deleted,
+}
+
+impl WebhookEvent {
+    /// All events.
+    pub const ALL: &'static [WebhookEvent] = &[WebhookEvent::Uploaded, WebhookEvent::Updated, WebhookEvent::Deleted];
+
+    /// Returns the event name used in payloads and subscriptions.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::Uploaded => "media.uploaded",
+            WebhookEvent::Updated => "media.updated",
+            WebhookEvent::Deleted => "media.deleted",
+        }
+    }
+}
+
+/// JSON payload of webhook requests.
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    event: &'a str,
+    occurred_at: String,
+    media: ShowMediaResponse,
+}
+
+/// Queues the event for webhooks subscribing it.
+/// Failures are only logged so that they don't fail the originating request.
+pub async fn notify(state: &State, event: WebhookEvent, media: &Media) {
+    if let Err(e) = enqueue(state, event, media).await {
+        error!("Failed to queue {} of {}: {}", event.as_str(), media.hash_id, e);
+    }
+}
+
+async fn enqueue(state: &State, event: WebhookEvent, media: &Media) -> Result<()> {
+    let payload = Payload {
+        event: event.as_str(),
+        occurred_at: OffsetDateTime::now_local()?.format(&Rfc3339)?,
+        media: ShowMediaResponse::from_media_record(state, media)?,
+    };
+    let queued = insert_webhook_deliveries(&state.pool, event.as_str(), &serde_json::to_string(&payload)?).await?;
+    if queued > 0 {
+        info!("Queued {} of {} for {} webhook(s)", event.as_str(), media.hash_id, queued);
+    }
+    Ok(())
+}
+
+/// Generates a random secret for a new webhook.
+pub fn generate_secret() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SECRET_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Calculates the signature sent in `X-Kebisafe-Signature`.
+/// The message is `{timestamp}.{body}` so that receivers can reject replayed requests.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={}", HEXLOWER.encode(&mac.finalize().into_bytes()))
+}
+
+/// Returns the delay before the next attempt after `attempts` failures.
+fn retry_delay(attempts: i32) -> Duration {
+    let exponent = (attempts - 1).clamp(0, 16) as u32;
+    let delay = RETRY_BASE_DELAY * 2i32.pow(exponent);
+    if delay > RETRY_MAX_DELAY {
+        RETRY_MAX_DELAY
+    } else {
+        delay
+    }
+}
+
+/// Attempts the delivery once and records the result.
+/// Non-2xx responses and connection errors are retried with exponential backoff.
+pub async fn deliver(state: &State, webhook: &Webhook, delivery: WebhookDelivery) -> Result<WebhookDelivery> {
+    let now = OffsetDateTime::now_local()?;
+    let delivery = attempt(&state.webhook_client, webhook, delivery, state.webhook_max_attempts, now).await;
+    update_webhook_delivery(&state.pool, &delivery).await?;
+    Ok(delivery)
+}
+
+/// Sends the delivery once and advances its status.
+async fn attempt(client: &Client, webhook: &Webhook, mut delivery: WebhookDelivery, max_attempts: i32, now: OffsetDateTime) -> WebhookDelivery {
+    let timestamp = now.unix_timestamp();
+    delivery.attempts += 1;
+
+    let result = client
+        .post(&webhook.url)
+        .header("User-Agent", "Kebisafe-Webhook")
+        .header("X-Kebisafe-Event", delivery.event.as_str())
+        .header("X-Kebisafe-Delivery", delivery.id.to_string())
+        .header("X-Kebisafe-Timestamp", timestamp.to_string())
+        .header("X-Kebisafe-Signature", sign(&webhook.secret, timestamp, &delivery.payload))
+        .body_string(delivery.payload.clone())
+        .content_type(mime::JSON)
+        .await;
+
+    let succeeded = match result {
+        Ok(response) => {
+            delivery.response_status = Some(response.status() as i32);
+            delivery.last_error = None;
+            response.status().is_success()
+        }
+        Err(e) => {
+            delivery.response_status = None;
+            delivery.last_error = Some(e.to_string());
+            false
+        }
+    };
+
+    if succeeded {
+        delivery.status = STATUS_SUCCEEDED.to_string();
+        delivery.delivered_at = Some(now);
+    } else if delivery.attempts >= max_attempts {
+        delivery.status = STATUS_FAILED.to_string();
+    } else {
+        delivery.next_attempt_at = now + retry_delay(delivery.attempts);
+    }
+    delivery
+}
+
+/// Gives up the delivery without attempting it.
+pub async fn abandon(state: &State, mut delivery: WebhookDelivery, reason: &str) -> Result<()> {
+    delivery.status = STATUS_FAILED.to_string();
+    delivery.last_error = Some(reason.to_string());
+    update_webhook_delivery(&state.pool, &delivery).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_std::{
+        io::{prelude::*, ReadExt},
+        net::TcpListener,
+        task::{spawn, JoinHandle},
+    };
+
+    const SECRET: &str = "whsec_test";
+    const PAYLOAD: &str = r#"{"event":"media.uploaded"}"#;
+    const STATUS_PENDING: &str = "pending";
+
+    fn webhook(url: String) -> Webhook {
+        Webhook {
+            id: 1,
+            url,
+            secret: SECRET.to_string(),
+            events: vec![WebhookEvent::Uploaded.as_str().to_string()],
+            is_enabled: true,
+            created_at: OffsetDateTime::UNIX_EPOCH,
+        }
+    }
+
+    fn delivery(attempts: i32) -> WebhookDelivery {
+        WebhookDelivery {
+            id: 42,
+            webhook_id: 1,
+            event: WebhookEvent::Uploaded.as_str().to_string(),
+            payload: PAYLOAD.to_string(),
+            status: STATUS_PENDING.to_string(),
+            attempts,
+            next_attempt_at: OffsetDateTime::UNIX_EPOCH,
+            response_status: None,
+            last_error: None,
+            created_at: OffsetDateTime::UNIX_EPOCH,
+            delivered_at: None,
+        }
+    }
+
+    /// Accepts one request, responds with the status and returns the received head and body.
+    async fn serve_once(status: u16) -> (String, JoinHandle<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("Bindable");
+        let url = format!("http://{}/hook", listener.local_addr().expect("Bound"));
+        let handle = spawn(async move {
+            let (mut stream, _) = listener.accept().await.expect("Accepted");
+            let mut received = vec![];
+            let mut buffer = [0; 1024];
+            let head_end = loop {
+                let read = stream.read(&mut buffer).await.expect("Readable");
+                assert!(read > 0, "Request ended early");
+                received.extend_from_slice(&buffer[..read]);
+                if let Some(i) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break i + 4;
+                }
+            };
+            let head = String::from_utf8_lossy(&received[..head_end]).into_owned();
+            let length: usize = header_value(&head, "content-length").and_then(|v| v.parse().ok()).unwrap_or(0);
+            let mut body = received[head_end..].to_vec();
+            body.resize(length, 0);
+            let already = received.len() - head_end;
+            stream.read_exact(&mut body[already..]).await.expect("Readable");
+
+            let response = format!("HTTP/1.1 {} Test\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+            stream.write_all(response.as_bytes()).await.expect("Writable");
+            (head, String::from_utf8(body).expect("UTF-8 body"))
+        });
+        (url, handle)
+    }
+
+    fn header_value(head: &str, name: &str) -> Option<String> {
+        head.lines()
+            .filter_map(|l| l.split_once(':'))
+            .find(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.trim().to_string())
+    }
+
+    fn now() -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(1_700_000_000).expect("Valid timestamp")
+    }
+
+    #[test]
+    fn sign_matches_known_vector() {
+        assert_eq!(
+            sign(SECRET, 1_700_000_000, PAYLOAD),
+            "sha256=d594b329a905ba2ea07e284dfce3dd3a07a7c8694d3b7ad5a25e0ea85bd1ba44"
+        );
+        assert_ne!(sign(SECRET, 1_700_000_001, PAYLOAD), sign(SECRET, 1_700_000_000, PAYLOAD));
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_cap() {
+        assert_eq!(retry_delay(0), Duration::seconds(30));
+        assert_eq!(retry_delay(1), Duration::seconds(30));
+        assert_eq!(retry_delay(2), Duration::seconds(60));
+        assert_eq!(retry_delay(10), Duration::seconds(30 * 512));
+        assert_eq!(retry_delay(11), RETRY_MAX_DELAY);
+        assert_eq!(retry_delay(i32::MAX), RETRY_MAX_DELAY);
+    }
+
+    #[async_std::test]
+    async fn attempt_sends_signed_request_and_succeeds() {
+        let (url, server) = serve_once(204).await;
+        let delivery = attempt(&Client::new(), &webhook(url), delivery(0), 3, now()).await;
+        let (head, body) = server.await;
+
+        assert!(head.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert_eq!(body, PAYLOAD);
+        assert_eq!(header_value(&head, "x-kebisafe-event").as_deref(), Some("media.uploaded"));
+        assert_eq!(header_value(&head, "x-kebisafe-delivery").as_deref(), Some("42"));
+        assert_eq!(header_value(&head, "x-kebisafe-timestamp").as_deref(), Some("1700000000"));
+        assert_eq!(
+            header_value(&head, "x-kebisafe-signature"),
+            Some(sign(SECRET, now().unix_timestamp(), PAYLOAD))
+        );
+
+        assert_eq!(delivery.status, STATUS_SUCCEEDED);
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.response_status, Some(204));
+        assert_eq!(delivery.delivered_at, Some(now()));
+    }
+
+    #[async_std::test]
+    async fn attempt_keeps_pending_until_max_attempts() {
+        let (url, server) = serve_once(500).await;
+        let delivery = attempt(&Client::new(), &webhook(url), delivery(1), 3, now()).await;
+        server.await;
+
+        assert_eq!(delivery.status, STATUS_PENDING);
+        assert_eq!(delivery.attempts, 2);
+        assert_eq!(delivery.response_status, Some(500));
+        assert_eq!(delivery.next_attempt_at, now() + Duration::seconds(60));
+        assert_eq!(delivery.delivered_at, None);
+
+        let (url, server) = serve_once(500).await;
+        let delivery = attempt(&Client::new(), &webhook(url), delivery, 3, now()).await;
+        server.await;
+
+        assert_eq!(delivery.status, STATUS_FAILED);
+        assert_eq!(delivery.attempts, 3);
+    }
+
+    #[async_std::test]
+    async fn attempt_records_connection_errors() {
+        // The port is closed again once the listener is dropped
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("Bindable");
+        let url = format!("http://{}/hook", listener.local_addr().expect("Bound"));
+        drop(listener);
+
+        let delivery = attempt(&Client::new(), &webhook(url), delivery(0), 3, now()).await;
+        assert_eq!(delivery.status, STATUS_PENDING);
+        assert_eq!(delivery.response_status, None);
+        assert!(delivery.last_error.is_some());
+        assert_eq!(delivery.next_attempt_at, now() + Duration::seconds(30));
+    }
+}
@@ -2,21 +2,28 @@
 
 use crate::{
     action::{
-        database::{fetch_media, reserve_media_record},
-        media::{create_thumbnail, save_image, validate_image_file},
+        database::{fetch_media, fetch_media_by_key, fetch_similar_media_list, SlugTakenError},
+        media::{check_slug, store_media, validate_image_file, StoredMedia, UploadOptions},
+        webhook::{notify, WebhookEvent},
     },
-    api::schema::{ErrorResponse, ShowMediaQuery, ShowMediaResponse, UploadMediaQuery},
+    api::schema::{ErrorResponse, ShowMediaQuery, ShowMediaResponse, SimilarMediaQuery, SimilarMediaResponse, UploadMediaQuery},
     application::State,
 };
 
-use async_std::{sync::Arc, task::spawn};
+use async_std::sync::Arc;
+use std::time::Instant;
 
-use image::ImageFormat;
+use anyhow::Result;
 use log::debug;
 use tide::{
     http::{mime, StatusCode},
     Request, Response, Result as TideResult,
 };
+use time::OffsetDateTime;
+
+const SIMILAR_DEFAULT_THRESHOLD: u32 = 10;
+const SIMILAR_DEFAULT_LIMIT: usize = 20;
+const SIMILAR_MAX_LIMIT: usize = 100;
 
 /// `GET /api/show`
 pub async fn show(request: Request<Arc<State>>) -> TideResult {
@@ -25,7 +32,7 @@ pub async fn show(request: Request<Arc<State>>) -> TideResult {
     let query: ShowMediaQuery = request.query()?;
     let state = request.state().clone();
 
-    let media_record = match fetch_media(&state.pool, &query.hash_id).await? {
+    let media_record = match fetch_media_by_key(&state.pool, &query.hash_id).await? {
         Some(m) => m,
         None => {
             return Ok(ErrorResponse::build(
@@ -34,6 +41,12 @@ pub async fn show(request: Request<Arc<State>>) -> TideResult {
             )?);
         }
     };
+    if media_record.is_expired(OffsetDateTime::now_local()?) {
+        return Ok(ErrorResponse::build(
+            StatusCode::Gone,
+            format!("Media #{} has expired", query.hash_id),
+        )?);
+    }
 
     Ok(Response::builder(StatusCode::Ok)
         .content_type(mime::JSON)
@@ -44,6 +57,42 @@ pub async fn show(request: Request<Arc<State>>) -> TideResult {
         .build())
 }
 
+/// `GET /api/similar`
+pub async fn similar(request: Request<Arc<State>>) -> TideResult {
+    debug!("API endpoint: GET /api/similar");
+
+    let query: SimilarMediaQuery = request.query()?;
+    let state = request.state().clone();
+
+    let media_record = match fetch_media(&state.pool, &query.hash_id).await? {
+        Some(m) => m,
+        None => {
+            return Ok(ErrorResponse::build(
+                StatusCode::NotFound,
+                format!("Media #{} not found", query.hash_id),
+            )?);
+        }
+    };
+    let threshold = query.threshold.unwrap_or(SIMILAR_DEFAULT_THRESHOLD).min(64);
+    let limit = query.limit.unwrap_or(SIMILAR_DEFAULT_LIMIT).min(SIMILAR_MAX_LIMIT);
+
+    let similar_list = fetch_similar_media_list(&state.pool, &media_record, threshold, true, limit).await?;
+    let response = similar_list
+        .iter()
+        .map(|(media, distance)| {
+            Ok(SimilarMediaResponse {
+                distance: *distance,
+                media: ShowMediaResponse::from_media_record(&state, media)?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(mime::JSON)
+        .body(serde_json::to_string(&response)?)
+        .build())
+}
+
 pub async fn upload(mut request: Request<Arc<State>>) -> TideResult {
     debug!("API endpoint: GET /api/upload");
 
@@ -51,7 +100,10 @@ pub async fn upload(mut request: Request<Arc<State>>) -> TideResult {
     let state = request.state().clone();
     let body = request.body_bytes().await?;
 
-    let validated_image = match validate_image_file(&query.filename, &body) {
+    let decode_started = Instant::now();
+    let validated_image = validate_image_file(&query.filename, &body);
+    state.metrics.observe_decode(decode_started.elapsed());
+    let validated_image = match validated_image {
         Ok(image) => image,
         Err(e) => {
             return Ok(ErrorResponse::build(
@@ -60,24 +112,47 @@ pub async fn upload(mut request: Request<Arc<State>>) -> TideResult {
             )?);
         }
     };
-    let thumbnail = create_thumbnail(&validated_image.image);
-
-    let record = reserve_media_record(
-        &state.pool,
-        &validated_image,
-        thumbnail.is_some(),
-        query.private.unwrap_or_default(),
-    )
-    .await?;
-    let filename = state.media_root.join(format!("{}.{}", record.hash_id, record.extension));
-    let thumb_filename = state.media_root.join(format!("thumbnails/{}.jpg", record.hash_id));
-    if let Some(thumb) = thumbnail {
-        spawn(async move { save_image(&thumb, ImageFormat::Jpeg, &thumb_filename) }).await?;
-        spawn(async move { save_image(&validated_image.image, validated_image.format, &filename) }).await?;
-    } else {
-        spawn(async move { save_image(&validated_image.image, validated_image.format, &filename) }).await?;
+    if let Some(expires_at) = query.expires_at {
+        if expires_at <= OffsetDateTime::now_local()? {
+            return Ok(ErrorResponse::build(StatusCode::BadRequest, "Expiration date is in the past")?);
+        }
+    }
+    let max_views = match query.max_views.map(i32::try_from).transpose() {
+        Ok(Some(0)) => return Ok(ErrorResponse::build(StatusCode::BadRequest, "Maximum views must be positive")?),
+        Ok(v) => v,
+        Err(_) => return Ok(ErrorResponse::build(StatusCode::BadRequest, "Maximum views is too large")?),
+    };
+
+    let slug = query.slug.as_deref().map(|s| s.trim()).filter(|s| !s.is_empty());
+    if let Some(slug) = slug {
+        if let Err(e) = check_slug(&state.pool, slug, None).await {
+            return Ok(ErrorResponse::build(StatusCode::BadRequest, format!("Invalid slug: {}", e))?);
+        }
     }
 
+    let options = UploadOptions {
+        private: query.private.unwrap_or_default(),
+        strip_exif: query.strip_exif.unwrap_or_default(),
+        expires_at: query.expires_at,
+        max_views,
+        comment: query.comment.clone(),
+        slug: slug.map(|s| s.to_string()),
+        ..Default::default()
+    };
+    let stored = match store_media(&state, validated_image, &options).await {
+        Err(e) if e.is::<SlugTakenError>() => {
+            return Ok(ErrorResponse::build(StatusCode::BadRequest, format!("Invalid slug: {}", e))?);
+        }
+        stored => stored?,
+    };
+    let record = match stored {
+        StoredMedia::Created(record) => {
+            notify(&state, WebhookEvent::Uploaded, &record).await;
+            record
+        }
+        StoredMedia::Existing(record) => record,
+    };
+
     Ok(Response::builder(StatusCode::Ok)
         .content_type(mime::JSON)
         .body(serde_json::to_string(&ShowMediaResponse::from_media_record(&state, &record)?)?)
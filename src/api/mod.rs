@@ -3,7 +3,9 @@
 pub(crate) mod endpoint;
 pub(crate) mod schema;
 
-use crate::api::schema::ErrorResponse;
+use crate::{api::schema::ErrorResponse, metrics::Metrics};
+
+use async_std::sync::Arc;
 
 use async_trait::async_trait;
 use tide::{http::StatusCode, Middleware, Next, Request, Result as TideResult};
@@ -11,12 +13,16 @@ use tide::{http::StatusCode, Middleware, Next, Request, Result as TideResult};
 /// Authorizes API call.
 pub struct ApiAuthorizationMiddleware {
     token: String,
+    metrics: Arc<Metrics>,
 }
 
 impl ApiAuthorizationMiddleware {
     /// Constructs a new middleware.
-    pub fn new(token: &str) -> ApiAuthorizationMiddleware {
-        ApiAuthorizationMiddleware { token: token.into() }
+    pub fn new(token: &str, metrics: Arc<Metrics>) -> ApiAuthorizationMiddleware {
+        ApiAuthorizationMiddleware {
+            token: token.into(),
+            metrics,
+        }
     }
 }
 
@@ -30,10 +36,16 @@ impl<State: 'static + Send + Sync + Clone> Middleware<State> for ApiAuthorizatio
                 let token = header_values.next();
                 match (auth_type, token) {
                     (Some("Bearer"), Some(t)) if t == self.token => (),
-                    _ => return Ok(ErrorResponse::build(StatusCode::Forbidden, "Authorization failed")?),
+                    _ => {
+                        self.metrics.record_auth_failure("api");
+                        return Ok(ErrorResponse::build(StatusCode::Forbidden, "Authorization failed")?);
+                    }
                 }
             }
-            None => return Ok(ErrorResponse::build(StatusCode::Forbidden, "Authorization needed")?),
+            None => {
+                self.metrics.record_auth_failure("api");
+                return Ok(ErrorResponse::build(StatusCode::Forbidden, "Authorization needed")?);
+            }
         }
 
         let response = next.run(request).await;
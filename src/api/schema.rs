@@ -5,7 +5,7 @@ use crate::{application::State, entity::Media};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use tide::{http::StatusCode, Response};
-use time::OffsetDateTime;
+use time::{OffsetDateTime, PrimitiveDateTime};
 use url::Url;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
@@ -33,34 +33,76 @@ pub struct ShowMediaResponse {
     pub url: Url,
     pub permalink: Url,
     pub hash_id: String,
+    pub slug: Option<String>,
     pub width: usize,
     pub height: usize,
     pub filesize: usize,
     pub private: bool,
     pub comment: Option<String>,
     pub uploaded: OffsetDateTime,
+    pub expires_at: Option<OffsetDateTime>,
+    pub max_views: Option<usize>,
+    pub view_count: usize,
+    pub content_hash: Option<String>,
+    pub camera: Option<String>,
+    pub lens: Option<String>,
+    pub captured_at: Option<PrimitiveDateTime>,
+    pub orientation: Option<u16>,
+    pub blurhash: Option<String>,
+    pub dominant_color: Option<String>,
 }
 
 impl ShowMediaResponse {
     /// Constructs from `Meida`
     pub fn from_media_record(state: &State, media: &Media) -> Result<ShowMediaResponse> {
         Ok(ShowMediaResponse {
-            url: state.hosted_at.join(&format!("/m/{}", media.hash_id))?,
-            permalink: state.hosted_at.join(&format!("/media/{}.{}", media.hash_id, media.extension))?,
+            url: state.hosted_at.join(&format!("/m/{}", media.public_id()))?,
+            permalink: state.hosted_at.join(&format!("/media/{}.{}", media.public_id(), media.extension))?,
             hash_id: media.hash_id.clone(),
+            slug: media.slug.clone(),
             width: media.width as usize,
             height: media.height as usize,
             filesize: media.filesize as usize,
             private: media.is_private,
             comment: media.comment.clone(),
             uploaded: media.uploaded,
+            expires_at: media.expires_at,
+            max_views: media.max_views.map(|m| m as usize),
+            view_count: media.view_count as usize,
+            content_hash: media.content_hash.clone(),
+            camera: media.exif_camera.clone(),
+            lens: media.exif_lens.clone(),
+            captured_at: media.exif_captured_at,
+            orientation: media.exif_orientation.map(|o| o as u16),
+            blurhash: media.blurhash.clone(),
+            dominant_color: media.dominant_color.clone(),
         })
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct SimilarMediaQuery {
+    pub hash_id: String,
+    pub threshold: Option<u32>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SimilarMediaResponse {
+    pub distance: u32,
+    pub media: ShowMediaResponse,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 pub struct UploadMediaQuery {
     pub filename: String,
     pub private: Option<bool>,
     pub comment: Option<String>,
+
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub expires_at: Option<OffsetDateTime>,
+
+    pub max_views: Option<u32>,
+    pub strip_exif: Option<bool>,
+    pub slug: Option<String>,
 }
@@ -1,21 +1,29 @@
 //! Contains application common types.
 
+use crate::{action::hash_id::HashIdGenerator, metrics::Metrics};
+
 use async_std::{path::PathBuf, sync::Arc};
-use std::convert::TryFrom;
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    env, fs as sync_fs,
+    net::ToSocketAddrs,
+};
 
 use aes_gcm_siv::{
     aead::{generic_array::GenericArray, NewAead},
     Aes256GcmSiv,
 };
-use anyhow::Result;
+use anyhow::{bail, ensure, Context, Result};
 use clap::{Parser, Subcommand};
 use data_encoding::HEXLOWER_PERMISSIVE;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
+use time::Duration;
 use url::Url;
 
-/// Captured environment variables.
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+/// Captured environment variables, layered over the configuration file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Environments {
     pub secret_key: String,
     pub listen_at: String,
@@ -27,12 +35,287 @@ pub struct Environments {
     pub account_name: String,
     pub account_password: String,
     pub api_token: String,
+
+    #[serde(default = "Environments::default_trash_retention_days")]
+    pub trash_retention_days: u32,
+
+    #[serde(default)]
+    pub duplicate_policy: DuplicatePolicy,
+
+    #[serde(default)]
+    pub exif_policy: ExifPolicy,
+
+    #[serde(default)]
+    pub thumbnail_mode: ThumbnailMode,
+
+    #[serde(default = "Environments::default_thumbnail_background")]
+    pub thumbnail_background: String,
+
+    #[serde(default = "Environments::default_thumbnail_width")]
+    pub thumbnail_width: u32,
+
+    #[serde(default = "Environments::default_thumbnail_height")]
+    pub thumbnail_height: u32,
+
+    #[serde(default = "Environments::default_media_list_count")]
+    pub media_list_count: usize,
+
+    #[serde(default = "Environments::default_token_expiary")]
+    pub token_expiary: i64,
+
+    #[serde(default)]
+    pub hash_id_strategy: HashIdStrategy,
+
+    #[serde(default = "Environments::default_hash_alphabet")]
+    pub hash_alphabet: String,
+
+    #[serde(default = "Environments::default_hash_min_length")]
+    pub hash_min_length: usize,
+
+    #[serde(default = "Environments::default_hash_max_attempts")]
+    pub hash_max_attempts: usize,
+
+    #[serde(default = "Environments::default_session_ttl_days")]
+    pub session_ttl_days: u64,
+
+    #[serde(default = "Environments::default_webhook_timeout")]
+    pub webhook_timeout: u64,
+
+    #[serde(default = "Environments::default_webhook_max_attempts")]
+    pub webhook_max_attempts: i32,
+
+    #[serde(default)]
+    pub metrics_token: Option<String>,
+
+    #[serde(default)]
+    pub metrics_listen_at: Option<String>,
+
+    #[serde(default = "Environments::default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+}
+
+impl Environments {
+    /// Keys whose values are never printed.
+    pub const SECRET_KEYS: &'static [&'static str] = &["secret_key", "account_password", "api_token", "metrics_token"];
+
+    /// Keys whose values are URIs which may contain passwords.
+    pub const URI_KEYS: &'static [&'static str] = &["database_uri", "redis_uri"];
+
+    /// Loads from the configuration file and environment variables.
+    /// Environment variables take precedence over the file.
+    pub fn load(config: Option<&str>) -> Result<Environments> {
+        let mut variables = match config {
+            Some(path) => Environments::read_config_file(path)?,
+            None => HashMap::new(),
+        };
+        variables.extend(env::vars());
+
+        let envs = envy::from_iter(variables)?;
+        Ok(envs)
+    }
+
+    /// Reads a TOML configuration file as environment variable form.
+    /// Keys are the same as environment variables, and case-insensitive.
+    pub fn read_config_file(path: &str) -> Result<HashMap<String, String>> {
+        let content = sync_fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+        let table: toml::value::Table = toml::from_str(&content).with_context(|| format!("Failed to parse {}", path))?;
+
+        let mut variables = HashMap::new();
+        for (key, value) in table {
+            let value = match value {
+                toml::Value::String(s) => s,
+                toml::Value::Integer(i) => i.to_string(),
+                toml::Value::Float(f) => f.to_string(),
+                toml::Value::Boolean(b) => b.to_string(),
+                _ => bail!("Unsupported value for \"{}\" in {}", key, path),
+            };
+            variables.insert(key.to_uppercase(), value);
+        }
+        Ok(variables)
+    }
+
+    /// Validates values which cannot be checked by deserialization.
+    pub fn validate(&self) -> Result<()> {
+        Url::parse(&self.hosted_at).context("Invalid HOSTED_AT")?;
+        Url::parse(&self.database_uri).context("Invalid DATABASE_URI")?;
+        Url::parse(&self.redis_uri).context("Invalid REDIS_URI")?;
+        let secret_key = HEXLOWER_PERMISSIVE
+            .decode(self.secret_key.as_bytes())
+            .context("Invalid SECRET_KEY")?;
+        ensure!(secret_key.len() == 32, "SECRET_KEY must be 32 bytes");
+        parse_color(&self.thumbnail_background).context("Invalid THUMBNAIL_BACKGROUND")?;
+        ensure!(
+            self.thumbnail_width > 0 && self.thumbnail_height > 0,
+            "Thumbnail size must be positive"
+        );
+        ensure!(self.media_list_count > 0, "MEDIA_LIST_COUNT must be positive");
+        ensure!(self.token_expiary > 0, "TOKEN_EXPIARY must be positive");
+        self.hash_id_generator().context("Invalid hash ID configuration")?;
+        ensure!(self.session_ttl_days > 0, "SESSION_TTL_DAYS must be positive");
+        ensure!(self.webhook_timeout > 0, "WEBHOOK_TIMEOUT must be positive");
+        ensure!(self.webhook_max_attempts > 0, "WEBHOOK_MAX_ATTEMPTS must be positive");
+        if let Some(metrics_listen_at) = &self.metrics_listen_at {
+            metrics_listen_at.to_socket_addrs().context("Invalid METRICS_LISTEN_AT")?;
+            ensure!(*metrics_listen_at != self.listen_at, "METRICS_LISTEN_AT must differ from LISTEN_AT");
+        }
+        Ok(())
+    }
+
+    fn default_trash_retention_days() -> u32 {
+        30
+    }
+
+    fn default_thumbnail_background() -> String {
+        "#000000".into()
+    }
+
+    fn default_thumbnail_width() -> u32 {
+        320
+    }
+
+    fn default_thumbnail_height() -> u32 {
+        180
+    }
+
+    fn default_media_list_count() -> usize {
+        50
+    }
+
+    fn default_token_expiary() -> i64 {
+        86400
+    }
+
+    /// Constructs hash ID generator from the configuration.
+    pub fn hash_id_generator(&self) -> Result<HashIdGenerator> {
+        HashIdGenerator::new(
+            self.hash_id_strategy,
+            &self.hash_alphabet,
+            self.hash_min_length,
+            self.hash_max_attempts,
+        )
+    }
+
+    fn default_hash_alphabet() -> String {
+        "0123456789abcdefghijklmnopqrstuvwxyz".into()
+    }
+
+    fn default_hash_min_length() -> usize {
+        6
+    }
+
+    fn default_hash_max_attempts() -> usize {
+        10
+    }
+
+    fn default_session_ttl_days() -> u64 {
+        7
+    }
+
+    fn default_webhook_timeout() -> u64 {
+        10
+    }
+
+    fn default_webhook_max_attempts() -> i32 {
+        8
+    }
+
+    fn default_shutdown_timeout() -> u64 {
+        30
+    }
+}
+
+/// Behavior on uploading media whose content already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicatePolicy {
+    /// Always stores a copy
+    Copy,
+
+    /// Returns the existing media
+    Existing,
+
+    /// Creates a new media sharing the stored files
+    Link,
+}
+
+impl Default for DuplicatePolicy {
+    fn default() -> DuplicatePolicy {
+        DuplicatePolicy::Copy
+    }
+}
+
+/// Handling of EXIF metadata in uploaded images.
+/// GPS location and personal tags are always stripped regardless of this policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExifPolicy {
+    /// Records camera, lens, captured date and orientation
+    Keep,
+
+    /// Records nothing
+    Strip,
+}
+
+impl Default for ExifPolicy {
+    fn default() -> ExifPolicy {
+        ExifPolicy::Keep
+    }
+}
+
+/// Thumbnail generation mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailMode {
+    /// Scales down to fit in the thumbnail size, keeping aspect ratio
+    Fit,
+
+    /// Scales to cover the thumbnail size and crops the center
+    Fill,
+
+    /// Scales to cover the thumbnail size and crops the most detailed region
+    Entropy,
+
+    /// Scales down to fit in the thumbnail size and pads with the background color
+    Letterbox,
+}
+
+impl Default for ThumbnailMode {
+    fn default() -> ThumbnailMode {
+        ThumbnailMode::Fit
+    }
+}
+
+/// Strategy of generating hash IDs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashIdStrategy {
+    /// Random characters with repetition, lengthened on conflict
+    Random,
+
+    /// Encoded sequence number, like Sqids
+    Sqids,
+
+    /// ULID (ignores the alphabet)
+    Ulid,
+
+    /// Timestamp followed by random characters, sortable by creation time
+    Sortable,
+}
+
+impl Default for HashIdStrategy {
+    fn default() -> HashIdStrategy {
+        HashIdStrategy::Random
+    }
 }
 
 /// Minimal, single-user, and fast image upload service
 #[derive(Debug, Parser)]
 #[clap(version, author)]
 pub struct Arguments {
+    /// Configuration file (TOML), overridden by environment variables
+    #[clap(long)]
+    pub config: Option<String>,
+
     /// Executing subcommand (default to `serve`)
     #[clap(subcommand)]
     pub subcommand: Option<SubCommand>,
@@ -45,6 +328,61 @@ pub enum SubCommand {
 
     /// Generates password hash
     GeneratePassword,
+
+    /// Validates configuration and prints effective values
+    CheckConfig,
+
+    /// Rebuilds thumbnails and placeholders of all media from originals
+    RegenerateThumbnails {
+        /// Number of media fetched at once
+        #[clap(long, default_value = "100")]
+        batch_size: usize,
+
+        /// Number of media processed in parallel
+        #[clap(long, default_value = "4")]
+        parallelism: usize,
+    },
+
+    /// Imports images under a local directory recursively
+    Import {
+        /// Directory to import from
+        directory: String,
+
+        /// Uses file modification times as upload dates
+        #[clap(long)]
+        use_mtime: bool,
+
+        /// Marks imported media as private unless sidecar JSON specifies
+        #[clap(long)]
+        private: bool,
+
+        /// File recording finished paths, used to resume an interrupted import
+        #[clap(long)]
+        journal: Option<String>,
+
+        /// Only reports what would be imported
+        #[clap(long)]
+        dry_run: bool,
+    },
+
+    /// Exports all media and stored files into a tar archive
+    Export {
+        /// Path of the archive to write
+        output: String,
+    },
+
+    /// Restores media and stored files from an exported archive into an empty instance
+    Restore {
+        /// Path of the exported archive
+        archive: String,
+    },
+
+    /// Cross-checks media records against files in the media directory
+    Fsck {
+        /// Fixes found issues
+        #[clap(long)]
+        repair: bool,
+    },
 }
 
 /// Shared application state for the server.
@@ -64,17 +402,62 @@ pub struct State {
 
     /// Account's name and password hash.
     pub account: (String, String),
+
+    /// Period for which deleted media are kept in the trash
+    pub trash_retention: Duration,
+
+    /// Behavior on uploading duplicate media
+    pub duplicate_policy: DuplicatePolicy,
+
+    /// Handling of EXIF metadata
+    pub exif_policy: ExifPolicy,
+
+    /// Thumbnail generation mode
+    pub thumbnail_mode: ThumbnailMode,
+
+    /// Background color of letterboxed thumbnails
+    pub thumbnail_background: [u8; 3],
+
+    /// Maximum thumbnail size
+    pub thumbnail_size: (u32, u32),
+
+    /// Number of media shown in the list
+    pub media_list_count: usize,
+
+    /// Period for which CSRF tokens are valid
+    pub token_expiary: Duration,
+
+    /// Generator of new hash IDs
+    pub hash_id_generator: HashIdGenerator,
+
+    /// HTTP client for webhook deliveries
+    pub webhook_client: surf::Client,
+
+    /// Maximum number of attempts of a webhook delivery
+    pub webhook_max_attempts: i32,
+
+    /// Application metrics
+    pub metrics: Arc<Metrics>,
+
+    /// Bearer token required to read metrics
+    pub metrics_token: Option<String>,
 }
 
 impl State {
     /// Constructs new application state.
     pub async fn new(envs: &Environments) -> Result<(Arc<State>, Box<[u8]>)> {
+        envs.validate()?;
+
         let media_root = PathBuf::try_from(&envs.media_dir)?;
         let hosted_at = Url::parse(&envs.hosted_at)?;
         let secret_key = HEXLOWER_PERMISSIVE.decode(envs.secret_key.as_bytes())?.into_boxed_slice();
         let key_array = GenericArray::from_slice(&secret_key);
         let cipher = Aes256GcmSiv::new(key_array);
         let pool = PgPool::connect(&envs.database_uri).await?;
+        let thumbnail_background = parse_color(&envs.thumbnail_background)?;
+        let webhook_client = surf::Config::new()
+            .set_timeout(Some(std::time::Duration::from_secs(envs.webhook_timeout)))
+            .try_into()?;
 
         Ok((
             Arc::new(State {
@@ -83,8 +466,31 @@ impl State {
                 cipher,
                 pool,
                 account: (envs.account_name.clone(), envs.account_password.clone()),
+                trash_retention: Duration::days(envs.trash_retention_days as i64),
+                duplicate_policy: envs.duplicate_policy,
+                exif_policy: envs.exif_policy,
+                thumbnail_mode: envs.thumbnail_mode,
+                thumbnail_background,
+                thumbnail_size: (envs.thumbnail_width, envs.thumbnail_height),
+                media_list_count: envs.media_list_count,
+                token_expiary: Duration::seconds(envs.token_expiary),
+                hash_id_generator: envs.hash_id_generator()?,
+                webhook_client,
+                webhook_max_attempts: envs.webhook_max_attempts,
+                metrics: Arc::new(Metrics::new()),
+                metrics_token: envs.metrics_token.clone(),
             }),
             secret_key,
         ))
     }
 }
+
+/// Parses `#rrggbb` color.
+fn parse_color(color: &str) -> Result<[u8; 3]> {
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    let bytes = HEXLOWER_PERMISSIVE.decode(hex.as_bytes())?;
+    match bytes[..] {
+        [r, g, b] => Ok([r, g, b]),
+        _ => bail!("Invalid color: {}", color),
+    }
+}
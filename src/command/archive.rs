@@ -0,0 +1,317 @@
+//! Contains `export` and `restore` subcommands.
+
+use crate::{
+    action::{
+        database::{
+            advance_hash_id_sequence, fetch_all_records_count, fetch_all_slug_aliases, fetch_media_batch, insert_media_record,
+            insert_slug_alias,
+        },
+        media::STAGING_DIRECTORY,
+    },
+    application::{Environments, State},
+    entity::Media,
+};
+
+use async_std::{
+    fs,
+    path::{Path, PathBuf},
+    task::spawn,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self as sync_fs, File as SyncFile},
+    io::{BufReader as SyncBufReader, BufWriter as SyncBufWriter, Read, Write},
+    path::{Component, Path as SyncPath, PathBuf as SyncPathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+use rand::{distributions::Alphanumeric, prelude::*};
+use serde_json::Value as JsonValue;
+use tar::{Archive, Builder, EntryType, Header};
+
+const BATCH_SIZE: usize = 100;
+const MANIFEST_NAME: &str = "manifest.ndjson";
+const MEDIA_PREFIX: &str = "media";
+
+const SLUG_ALIASES_KEY: &str = "slug_aliases";
+
+/// Line of the manifest.
+/// Former slugs are kept with the record so that they keep redirecting after restoring.
+#[derive(Debug)]
+struct ManifestEntry {
+    media: Media,
+    slug_aliases: Vec<String>,
+}
+
+impl ManifestEntry {
+    /// Serializes into the record object with an additional `slug_aliases` field.
+    fn to_json(&self) -> Result<JsonValue> {
+        let mut value = serde_json::to_value(&self.media)?;
+        if !self.slug_aliases.is_empty() {
+            value[SLUG_ALIASES_KEY] = serde_json::to_value(&self.slug_aliases)?;
+        }
+        Ok(value)
+    }
+
+    /// Deserializes from the record object. Archives without `slug_aliases` are also accepted.
+    fn from_json(mut value: JsonValue) -> Result<ManifestEntry> {
+        let slug_aliases = match value.as_object_mut().and_then(|o| o.remove(SLUG_ALIASES_KEY)) {
+            Some(aliases) => serde_json::from_value(aliases)?,
+            None => vec![],
+        };
+        Ok(ManifestEntry {
+            media: serde_json::from_value(value)?,
+            slug_aliases,
+        })
+    }
+}
+
+/// Exports all media records and stored files into a tar archive.
+pub async fn export(envs: &Environments, output: impl AsRef<Path>) -> Result<()> {
+    debug!("Exporting instance");
+
+    let (state, _) = State::new(envs).await?;
+    let mut records = vec![];
+    let mut last_hash_id: Option<String> = None;
+    loop {
+        let batch = fetch_media_batch(&state.pool, last_hash_id.as_deref(), BATCH_SIZE).await?;
+        last_hash_id = match batch.last() {
+            Some(m) => Some(m.hash_id.clone()),
+            None => break,
+        };
+        records.extend(batch);
+    }
+    let mut slug_aliases: HashMap<String, Vec<String>> = HashMap::new();
+    for (hash_id, slug) in fetch_all_slug_aliases(&state.pool).await? {
+        slug_aliases.entry(hash_id).or_default().push(slug);
+    }
+    let entries: Vec<_> = records
+        .into_iter()
+        .map(|media| ManifestEntry {
+            slug_aliases: slug_aliases.remove(&media.hash_id).unwrap_or_default(),
+            media,
+        })
+        .collect();
+    println!("Exporting {} media", entries.len());
+
+    let media_root: SyncPathBuf = state.media_root.clone().into();
+    let output: SyncPathBuf = output.as_ref().to_path_buf().into();
+    let exported_files = spawn(async move { write_archive(&output, &media_root, &entries) }).await?;
+
+    println!("Finished exporting ({} files)", exported_files);
+    Ok(())
+}
+
+/// Restores media records and stored files from an archive created by `export`.
+/// The instance must not have any media, and hash IDs are preserved.
+pub async fn restore(envs: &Environments, archive: impl AsRef<Path>) -> Result<()> {
+    debug!("Restoring instance");
+
+    let (state, _) = State::new(envs).await?;
+    if fetch_all_records_count(&state.pool).await? > 0 {
+        bail!("Restoring requires an instance without media");
+    }
+
+    let staging_id: String = thread_rng().sample_iter(&Alphanumeric).take(16).map(char::from).collect();
+    let staging_root = state.media_root.join(STAGING_DIRECTORY).join(format!("restore-{}", staging_id));
+    fs::create_dir_all(&staging_root).await?;
+
+    let mut moved_files = vec![];
+    let result = restore_staged(&state, archive.as_ref(), &staging_root, &mut moved_files).await;
+    if result.is_err() {
+        for path in moved_files {
+            if let Err(e) = fs::remove_file(&path).await {
+                warn!("Failed to clean up {}: {}", path.display(), e);
+            }
+        }
+    }
+    fs::remove_dir_all(&staging_root).await?;
+
+    let restored = result?;
+    println!("Finished restoring {} media", restored);
+    Ok(())
+}
+
+/// Extracts the archive into the staging directory, then inserts records and moves files into place in a transaction.
+async fn restore_staged(state: &State, archive: &Path, staging_root: &Path, moved_files: &mut Vec<PathBuf>) -> Result<usize> {
+    let archive_path: SyncPathBuf = archive.to_path_buf().into();
+    let staging_path: SyncPathBuf = staging_root.to_path_buf().into();
+    let (entries, filenames) = spawn(async move { extract_archive(&archive_path, &staging_path) }).await?;
+    println!("Restoring {} media ({} files)", entries.len(), filenames.len());
+
+    let filename_set: HashSet<_> = filenames.iter().collect();
+    for ManifestEntry { media, .. } in &entries {
+        if !filename_set.contains(&media.original_filename()) {
+            println!(
+                "{}: original file {} is not in the archive",
+                media.hash_id,
+                media.original_filename()
+            );
+        }
+    }
+
+    let mut transaction = state.pool.begin().await?;
+    for ManifestEntry { media, .. } in &entries {
+        insert_media_record(&mut transaction, media)
+            .await
+            .with_context(|| format!("Failed to restore media {}", media.hash_id))?;
+    }
+    // Aliases refer to records, so they are inserted after all records
+    for ManifestEntry { media, slug_aliases } in &entries {
+        for slug in slug_aliases {
+            insert_slug_alias(&mut transaction, slug, &media.hash_id)
+                .await
+                .with_context(|| format!("Failed to restore former slug {} of media {}", slug, media.hash_id))?;
+        }
+    }
+    // Restored hash IDs may have consumed sequence numbers, which must not be generated again
+    let sequence = entries
+        .iter()
+        .filter_map(|e| state.hash_id_generator.decode_sequence(&e.media.hash_id))
+        .filter_map(|n| i64::try_from(n).ok())
+        .max();
+    if let Some(sequence) = sequence {
+        advance_hash_id_sequence(&mut transaction, sequence).await?;
+    }
+
+    fs::create_dir_all(state.media_root.join("thumbnails")).await?;
+    fs::create_dir_all(state.media_root.join("variants")).await?;
+    for filename in &filenames {
+        let destination = state.media_root.join(filename);
+        if destination.exists().await {
+            bail!("{} already exists in the media directory", filename);
+        }
+        fs::rename(staging_root.join(filename), &destination).await?;
+        moved_files.push(destination);
+    }
+
+    transaction.commit().await?;
+    Ok(entries.len())
+}
+
+/// Writes the manifest and stored files, and returns the number of files written.
+fn write_archive(output: &SyncPath, media_root: &SyncPath, entries: &[ManifestEntry]) -> Result<usize> {
+    let mut builder = Builder::new(SyncBufWriter::new(SyncFile::create(output)?));
+
+    // Manifest comes first so that restoring can read it before files
+    let mut manifest = vec![];
+    for entry in entries {
+        serde_json::to_writer(&mut manifest, &entry.to_json()?)?;
+        manifest.push(b'\n');
+    }
+    let mut header = Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs());
+    builder.append_data(&mut header, MANIFEST_NAME, manifest.as_slice())?;
+
+    let mut written_storages = HashSet::new();
+    let mut written_files = 0;
+    for ManifestEntry { media, .. } in entries {
+        if !written_storages.insert(&media.storage_id) {
+            continue;
+        }
+
+        for filename in stored_files(media) {
+            let path = media_root.join(&filename);
+            if !path.is_file() {
+                println!("{}: {} is missing, skipped", media.hash_id, filename);
+                continue;
+            }
+            builder.append_path_with_name(&path, format!("{}/{}", MEDIA_PREFIX, filename))?;
+            written_files += 1;
+        }
+    }
+
+    builder.into_inner()?.flush()?;
+    Ok(written_files)
+}
+
+/// Extracts stored files into the staging directory, and returns entries in the manifest and extracted filenames.
+/// Only regular files of the media in the manifest are accepted.
+fn extract_archive(archive_path: &SyncPath, staging_root: &SyncPath) -> Result<(Vec<ManifestEntry>, Vec<String>)> {
+    let mut archive = Archive::new(SyncBufReader::new(SyncFile::open(archive_path)?));
+    sync_fs::create_dir_all(staging_root.join("thumbnails"))?;
+    sync_fs::create_dir_all(staging_root.join("variants"))?;
+
+    let mut records = None;
+    let mut expected_filenames = HashSet::new();
+    let mut filenames = vec![];
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        // Links and other special entries could point outside of the media directory
+        if entry.header().entry_type() != EntryType::Regular {
+            bail!("Unexpected entry type in the archive: {}", path.display());
+        }
+
+        if path == SyncPath::new(MANIFEST_NAME) {
+            if records.is_some() {
+                bail!("Archive has multiple manifests");
+            }
+            let mut manifest = String::new();
+            entry.read_to_string(&mut manifest)?;
+            let parsed = manifest
+                .lines()
+                .filter(|l| !l.trim().is_empty())
+                .map(|l| {
+                    let value = serde_json::from_str(l).context("Invalid manifest")?;
+                    ManifestEntry::from_json(value).context("Invalid manifest")
+                })
+                .collect::<Result<Vec<ManifestEntry>>>()?;
+            expected_filenames = parsed.iter().flat_map(|e| stored_files(&e.media)).collect();
+            records = Some(parsed);
+            continue;
+        }
+
+        if records.is_none() {
+            bail!("Archive has files before the manifest");
+        }
+        let filename = match stored_filename(&path) {
+            Some(f) => f,
+            None => bail!("Unexpected entry in the archive: {}", path.display()),
+        };
+        // Each file must belong to a record in the manifest and appear only once
+        if !expected_filenames.remove(&filename) {
+            bail!("Unexpected file in the archive: {}", path.display());
+        }
+        entry.unpack(staging_root.join(&filename))?;
+        filenames.push(filename);
+    }
+
+    match records {
+        Some(records) => Ok((records, filenames)),
+        None => bail!("Archive has no manifest"),
+    }
+}
+
+/// Lists filenames of the stored files of the media, relative to the media root.
+fn stored_files(media: &Media) -> Vec<String> {
+    let mut filenames = vec![media.original_filename()];
+    if media.has_thumbnail {
+        filenames.push(media.thumbnail_filename());
+    }
+    filenames.extend(media.available_variants().iter().map(|v| media.variant_filename(v)));
+    filenames
+}
+
+/// Validates the path of an archive entry and returns the filename relative to the media root.
+/// Only `media/<file>`, `media/thumbnails/<file>` and `media/variants/<file>` are accepted.
+fn stored_filename(path: &SyncPath) -> Option<String> {
+    let components = path
+        .components()
+        .map(|c| match c {
+            Component::Normal(name) => name.to_str(),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    match components.as_slice() {
+        [prefix, name] if *prefix == MEDIA_PREFIX => Some(name.to_string()),
+        [prefix, "thumbnails", name] if *prefix == MEDIA_PREFIX => Some(format!("thumbnails/{}", name)),
+        [prefix, "variants", name] if *prefix == MEDIA_PREFIX => Some(format!("variants/{}", name)),
+        _ => None,
+    }
+}
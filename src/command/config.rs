@@ -0,0 +1,50 @@
+//! Contains `check-config` subcommand.
+
+use crate::application::Environments;
+
+use anyhow::Result;
+use url::Url;
+
+const REDACTED: &str = "<redacted>";
+
+/// Validates the configuration and prints effective values with secrets redacted.
+pub fn check_config(envs: &Environments, config: Option<&str>) -> Result<()> {
+    envs.validate()?;
+
+    let mut table = match toml::Value::try_from(envs)? {
+        toml::Value::Table(table) => table,
+        _ => unreachable!("Environments should be serialized as a table"),
+    };
+
+    // Keys which Kebisafe does not know are ignored, and most likely typos
+    if let Some(path) = config {
+        let mut unknown_keys: Vec<_> = Environments::read_config_file(path)?
+            .into_iter()
+            .map(|(k, _)| k.to_lowercase())
+            .filter(|k| !table.contains_key(k))
+            .collect();
+        unknown_keys.sort();
+        for key in unknown_keys {
+            println!("# Warning: unknown key \"{}\" in {}", key, path);
+        }
+    }
+
+    for key in Environments::SECRET_KEYS {
+        if let Some(value) = table.get_mut(*key) {
+            *value = toml::Value::String(REDACTED.into());
+        }
+    }
+    for key in Environments::URI_KEYS {
+        if let Some(toml::Value::String(value)) = table.get_mut(*key) {
+            if let Ok(mut url) = Url::parse(value) {
+                if url.password().is_some() && url.set_password(Some("redacted")).is_ok() {
+                    *value = url.to_string();
+                }
+            }
+        }
+    }
+
+    println!("# Effective configuration");
+    print!("{}", toml::to_string(&table)?);
+    Ok(())
+}
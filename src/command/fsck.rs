@@ -0,0 +1,196 @@
+//! Contains `fsck` subcommand.
+
+use crate::{
+    action::{
+        database::{
+            fetch_all_records_count, fetch_media_batch, remove_media_record, update_media_file_info, update_media_thumbnail_flag,
+            update_media_variants,
+        },
+        media::{list_staging_leftovers, STAGING_LEFTOVER_AGE},
+    },
+    application::{Environments, State},
+    entity::Media,
+};
+
+use async_std::{fs, path::Path, prelude::*};
+use std::collections::HashSet;
+
+use anyhow::Result;
+use image::io::Reader as ImageReader;
+use log::debug;
+
+const BATCH_SIZE: usize = 100;
+
+/// Cross-checks media records and files in the media directory.
+pub async fn fsck(envs: &Environments, repair: bool) -> Result<()> {
+    debug!("Checking storage consistency");
+
+    let (state, _) = State::new(envs).await?;
+    let total = fetch_all_records_count(&state.pool).await?;
+    println!("Checking {} media records{}", total, if repair { " (repair mode)" } else { "" });
+
+    let mut expected_files = HashSet::new();
+    let mut last_hash_id: Option<String> = None;
+    let mut issues = 0usize;
+    loop {
+        let batch = fetch_media_batch(&state.pool, last_hash_id.as_deref(), BATCH_SIZE).await?;
+        let last = match batch.last() {
+            Some(m) => m.hash_id.clone(),
+            None => break,
+        };
+
+        for media in &batch {
+            expected_files.insert(media.original_filename());
+            expected_files.insert(media.thumbnail_filename());
+            expected_files.extend(media.available_variants().iter().map(|v| media.variant_filename(v)));
+            issues += check_media(&state, media, repair).await?;
+        }
+        last_hash_id = Some(last);
+    }
+
+    for filename in list_files(&state.media_root, "")
+        .await?
+        .into_iter()
+        .chain(list_files(&state.media_root, "thumbnails").await?)
+        .chain(list_files(&state.media_root, "variants").await?)
+    {
+        if expected_files.contains(&filename) {
+            continue;
+        }
+        issues += 1;
+        println!("{}: orphan file", filename);
+        if repair {
+            fs::remove_file(state.media_root.join(&filename)).await?;
+            println!("  -> removed");
+        }
+    }
+
+    for path in list_staging_leftovers(&state.media_root, STAGING_LEFTOVER_AGE).await? {
+        issues += 1;
+        println!("{}: leftover of an interrupted upload", path.display());
+        if repair {
+            fs::remove_file(&path).await?;
+            println!("  -> removed");
+        }
+    }
+
+    if issues == 0 {
+        println!("No issues found");
+    } else if repair {
+        println!("Found and repaired {} issue(s)", issues);
+    } else {
+        println!("Found {} issue(s); run with --repair to fix them", issues);
+    }
+    Ok(())
+}
+
+/// Checks a media record against its files, and returns the number of issues.
+async fn check_media(state: &State, media: &Media, repair: bool) -> Result<usize> {
+    let original_path = state.media_root.join(media.original_filename());
+    let thumb_path = state.media_root.join(media.thumbnail_filename());
+
+    // Original file
+    let metadata = match fs::metadata(&original_path).await {
+        Ok(m) if m.is_file() => m,
+        _ => {
+            println!("{}: original file {} is missing", media.hash_id, media.original_filename());
+            if repair {
+                let mut conn = state.pool.acquire().await?;
+                remove_media_record(&mut conn, &media.hash_id).await?;
+                println!("  -> record removed");
+            }
+            return Ok(1);
+        }
+    };
+
+    let mut issues = 0;
+
+    // Filesize and dimensions
+    let filesize = metadata.len() as i32;
+    let (width, height) = match ImageReader::open(&original_path).and_then(|r| r.with_guessed_format()) {
+        Ok(reader) => match reader.into_dimensions() {
+            Ok((w, h)) => (w as i32, h as i32),
+            Err(e) => {
+                println!("{}: cannot read dimensions: {}", media.hash_id, e);
+                (media.width, media.height)
+            }
+        },
+        Err(e) => {
+            println!("{}: cannot open original file: {}", media.hash_id, e);
+            (media.width, media.height)
+        }
+    };
+    if filesize != media.filesize || width != media.width || height != media.height {
+        issues += 1;
+        println!(
+            "{}: recorded {} bytes, {}x{} but actually {} bytes, {}x{}",
+            media.hash_id, media.filesize, media.width, media.height, filesize, width, height
+        );
+        if repair {
+            update_media_file_info(&state.pool, &media.hash_id, filesize, width, height).await?;
+            println!("  -> record updated");
+        }
+    }
+
+    // Thumbnail
+    let thumbnail_exists = thumb_path.is_file().await;
+    if thumbnail_exists != media.has_thumbnail {
+        issues += 1;
+        println!(
+            "{}: thumbnail flag is {} but thumbnail file {}",
+            media.hash_id,
+            media.has_thumbnail,
+            if thumbnail_exists { "exists" } else { "is missing" }
+        );
+        if repair {
+            update_media_thumbnail_flag(&state.pool, &media.hash_id, thumbnail_exists).await?;
+            println!("  -> flag set to {}", thumbnail_exists);
+        }
+    }
+
+    // Derivatives
+    let mut existing_variants = vec![];
+    for variant in media.available_variants() {
+        if state.media_root.join(media.variant_filename(variant)).is_file().await {
+            existing_variants.push(variant.clone());
+        } else {
+            issues += 1;
+            println!(
+                "{}: {} derivative {} is missing",
+                media.hash_id,
+                variant,
+                media.variant_filename(variant)
+            );
+        }
+    }
+    if repair && existing_variants.len() != media.available_variants().len() {
+        update_media_variants(&state.pool, &media.storage_id, &existing_variants).await?;
+        println!("  -> derivatives updated");
+    }
+
+    Ok(issues)
+}
+
+/// Lists regular files directly under the subdirectory, as paths relative to the media root.
+async fn list_files(media_root: &Path, subdirectory: &str) -> Result<Vec<String>> {
+    let directory = media_root.join(subdirectory);
+    if !directory.is_dir().await {
+        return Ok(vec![]);
+    }
+
+    let mut filenames = vec![];
+    let mut entries = fs::read_dir(&directory).await?;
+    while let Some(entry) = entries.next().await {
+        let entry = entry?;
+        if !entry.file_type().await?.is_file() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if subdirectory.is_empty() {
+            filenames.push(name);
+        } else {
+            filenames.push(format!("{}/{}", subdirectory, name));
+        }
+    }
+    Ok(filenames)
+}
@@ -0,0 +1,191 @@
+//! Contains `import` subcommand.
+
+use crate::{
+    action::{
+        database::fetch_media_by_content_hash,
+        media::{store_media, validate_image_file, StoredMedia, UploadOptions},
+    },
+    application::{Environments, State},
+};
+
+use async_std::{
+    fs::{self, OpenOptions},
+    path::{Path, PathBuf},
+    prelude::*,
+    task::spawn,
+};
+use std::collections::HashSet;
+
+use anyhow::{Context, Result};
+use log::debug;
+use serde::Deserialize;
+use time::OffsetDateTime;
+
+/// Options of `import` subcommand.
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    pub use_mtime: bool,
+    pub private: bool,
+    pub journal: Option<PathBuf>,
+    pub dry_run: bool,
+}
+
+/// Sidecar JSON placed as `<image filename>.json`.
+#[derive(Debug, Default, Deserialize)]
+struct Sidecar {
+    comment: Option<String>,
+    private: Option<bool>,
+
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+/// Outcome of importing a file.
+enum Imported {
+    Created(String),
+    Duplicate(String),
+}
+
+/// Imports images under the directory recursively.
+pub async fn import(envs: &Environments, directory: impl AsRef<Path>, options: &ImportOptions) -> Result<()> {
+    let directory = directory.as_ref();
+    debug!("Importing images from {}", directory.display());
+
+    let (state, _) = State::new(envs).await?;
+    let files = collect_files(directory).await?;
+
+    let finished = match &options.journal {
+        Some(journal) if journal.is_file().await => {
+            let content = fs::read_to_string(journal).await?;
+            content.lines().map(|l| l.to_string()).collect()
+        }
+        _ => HashSet::new(),
+    };
+    let mut journal = match (&options.journal, options.dry_run) {
+        (Some(journal), false) => Some(OpenOptions::new().create(true).append(true).open(journal).await?),
+        _ => None,
+    };
+
+    println!(
+        "Importing {} files ({} already done){}",
+        files.len(),
+        finished.len(),
+        if options.dry_run { " (dry run)" } else { "" }
+    );
+
+    let (mut created, mut duplicated, mut failed) = (0usize, 0usize, 0usize);
+    for (i, path) in files.iter().enumerate() {
+        let key = path.to_string_lossy().into_owned();
+        if finished.contains(&key) {
+            continue;
+        }
+
+        match import_file(&state, path, options).await {
+            Ok(Imported::Created(hash_id)) => {
+                created += 1;
+                println!("[{}/{}] {}: imported as {}", i + 1, files.len(), key, hash_id);
+            }
+            Ok(Imported::Duplicate(hash_id)) => {
+                duplicated += 1;
+                println!("[{}/{}] {}: skipped, duplicate of {}", i + 1, files.len(), key, hash_id);
+            }
+            Err(e) => {
+                failed += 1;
+                println!("[{}/{}] {}: failed: {:#}", i + 1, files.len(), key, e);
+                continue;
+            }
+        }
+
+        if let Some(journal) = &mut journal {
+            journal.write_all(format!("{}\n", key).as_bytes()).await?;
+            journal.flush().await?;
+        }
+    }
+
+    println!(
+        "Finished importing: {} {}, {} duplicated, {} failed",
+        created,
+        if options.dry_run { "to be imported" } else { "imported" },
+        duplicated,
+        failed
+    );
+    Ok(())
+}
+
+/// Imports a file.
+async fn import_file(state: &State, path: &Path, options: &ImportOptions) -> Result<Imported> {
+    let data = fs::read(path).await?;
+    let filename = path.to_path_buf();
+    let validated_image = spawn(async move { validate_image_file(&filename, &data) }).await?;
+
+    if let Some(existing) = fetch_media_by_content_hash(&state.pool, &validated_image.content_hash).await? {
+        return Ok(Imported::Duplicate(existing.hash_id));
+    }
+
+    let sidecar = read_sidecar(path).await?;
+    if !sidecar.tags.is_empty() {
+        println!("{}: tags are not supported and ignored", path.display());
+    }
+    let uploaded_at = if options.use_mtime {
+        let modified = fs::metadata(path).await?.modified()?;
+        Some(OffsetDateTime::from(modified))
+    } else {
+        None
+    };
+
+    if options.dry_run {
+        return Ok(Imported::Created("(dry run)".into()));
+    }
+
+    let upload_options = UploadOptions {
+        private: sidecar.private.unwrap_or(options.private),
+        comment: sidecar.comment,
+        uploaded_at,
+        ..Default::default()
+    };
+    match store_media(state, validated_image, &upload_options).await? {
+        StoredMedia::Created(record) => Ok(Imported::Created(record.hash_id)),
+        StoredMedia::Existing(existing) => Ok(Imported::Duplicate(existing.hash_id)),
+    }
+}
+
+/// Reads the sidecar JSON of the image if exists.
+async fn read_sidecar(path: &Path) -> Result<Sidecar> {
+    let mut sidecar_path = path.as_os_str().to_owned();
+    sidecar_path.push(".json");
+    let sidecar_path = PathBuf::from(sidecar_path);
+    if !sidecar_path.is_file().await {
+        return Ok(Sidecar::default());
+    }
+
+    let content = fs::read_to_string(&sidecar_path).await?;
+    let sidecar = serde_json::from_str(&content).with_context(|| format!("Invalid sidecar {}", sidecar_path.display()))?;
+    Ok(sidecar)
+}
+
+/// Lists candidate files under the directory recursively, in sorted order.
+/// Hidden files and sidecar JSONs are excluded.
+async fn collect_files(directory: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut directories = vec![directory.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        let mut entries = fs::read_dir(&directory).await?;
+        while let Some(entry) = entries.next().await {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+
+            let file_type = entry.file_type().await?;
+            let path = entry.path();
+            if file_type.is_dir() {
+                directories.push(path);
+            } else if file_type.is_file() && path.extension().map(|e| e != "json").unwrap_or(true) {
+                files.push(path);
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
@@ -0,0 +1,7 @@
+//! Contains maintenance subcommands.
+
+pub(crate) mod archive;
+pub(crate) mod config;
+pub(crate) mod fsck;
+pub(crate) mod import;
+pub(crate) mod regenerate;
@@ -0,0 +1,113 @@
+//! Contains `regenerate-thumbnails` subcommand.
+
+use crate::{
+    action::{
+        database::{fetch_all_records_count, fetch_media_batch, update_media_derivatives},
+        media::{compute_blurhash, compute_dominant_color, compute_perceptual_hash, create_thumbnail, generate_variants, save_image},
+    },
+    application::{Environments, State},
+    entity::Media,
+};
+
+use async_std::{fs, sync::Arc, task::spawn};
+use std::collections::HashSet;
+
+use anyhow::{format_err, Result};
+use futures::stream::{self, StreamExt};
+use image::ImageFormat;
+use log::{debug, error};
+
+/// Rebuilds thumbnails, placeholders and WebP/AVIF derivatives of all media from stored originals.
+pub async fn regenerate_thumbnails(envs: &Environments, batch_size: usize, parallelism: usize) -> Result<()> {
+    debug!("Regenerating thumbnails");
+
+    let (state, _) = State::new(envs).await?;
+    let total = fetch_all_records_count(&state.pool).await?;
+    println!("Regenerating derivatives of {} media", total);
+
+    let mut processed_storages = HashSet::new();
+    let mut last_hash_id: Option<String> = None;
+    let (mut processed, mut failed) = (0usize, 0usize);
+    loop {
+        let batch = fetch_media_batch(&state.pool, last_hash_id.as_deref(), batch_size.max(1)).await?;
+        let last = match batch.last() {
+            Some(m) => m.hash_id.clone(),
+            None => break,
+        };
+
+        // Media sharing stored files are regenerated at once
+        let batch_len = batch.len();
+        let targets: Vec<_> = batch
+            .into_iter()
+            .filter(|m| processed_storages.insert(m.storage_id.clone()))
+            .collect();
+        let results: Vec<_> = stream::iter(targets)
+            .map(|media| regenerate_media(state.clone(), media))
+            .buffer_unordered(parallelism.max(1))
+            .collect()
+            .await;
+        for error in results.into_iter().filter_map(|r| r.err()) {
+            failed += 1;
+            error!("{}", error);
+        }
+
+        processed += batch_len;
+        println!("Processed {}/{} ({} failed)", processed, total, failed);
+
+        last_hash_id = Some(last);
+    }
+
+    println!("Finished regenerating derivatives ({} failed)", failed);
+    Ok(())
+}
+
+/// Rebuilds derivatives of a media and updates its record.
+async fn regenerate_media(state: Arc<State>, media: Media) -> Result<()> {
+    let original_path = state.media_root.join(media.original_filename());
+    let thumb_path = state.media_root.join(media.thumbnail_filename());
+    let format = ImageFormat::from_extension(&media.extension)
+        .ok_or_else(|| format_err!("Media {}: unknown extension {}", media.hash_id, media.extension))?;
+    let data = fs::read(&original_path)
+        .await
+        .map_err(|e| format_err!("Media {}: cannot read original: {}", media.hash_id, e))?;
+
+    let mode = state.thumbnail_mode;
+    let size = state.thumbnail_size;
+    let background = state.thumbnail_background;
+    let hash_id = media.hash_id.clone();
+    let (has_thumbnail, perceptual_hash, blurhash, dominant_color) = spawn(async move {
+        let image =
+            image::load_from_memory_with_format(&data, format).map_err(|e| format_err!("Media {}: cannot decode original: {}", hash_id, e))?;
+        let thumbnail = create_thumbnail(&image, mode, size, background);
+        if let Some(thumb) = &thumbnail {
+            save_image(thumb, ImageFormat::Jpeg, &thumb_path)?;
+        }
+        Ok::<_, anyhow::Error>((
+            thumbnail.is_some(),
+            compute_perceptual_hash(&image),
+            compute_blurhash(&image),
+            compute_dominant_color(&image),
+        ))
+    })
+    .await?;
+
+    // Stale thumbnail is no longer needed
+    if !has_thumbnail && media.has_thumbnail {
+        fs::remove_file(state.media_root.join(media.thumbnail_filename())).await.ok();
+    }
+
+    update_media_derivatives(
+        &state.pool,
+        &media.storage_id,
+        has_thumbnail,
+        perceptual_hash,
+        blurhash.as_deref(),
+        &dominant_color,
+    )
+    .await?;
+
+    generate_variants(&state.pool, &state.media_root, &media)
+        .await
+        .map_err(|e| format_err!("Media {}: cannot generate derivatives: {}", media.hash_id, e))?;
+    Ok(())
+}
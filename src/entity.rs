@@ -1,8 +1,10 @@
+use serde::{Deserialize, Serialize};
 use sqlx::prelude::*;
-use time::OffsetDateTime;
+use time::{OffsetDateTime, PrimitiveDateTime};
 
 /// Represents a media record.
-#[derive(Debug, Clone, FromRow)]
+/// Serialized form is used in export archives.
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
 pub struct Media {
     /// Short hash ID
     pub hash_id: String,
@@ -29,11 +31,116 @@ pub struct Media {
     pub comment: Option<String>,
 
     /// Uploaded date
+    #[serde(with = "time::serde::rfc3339")]
     pub uploaded: OffsetDateTime,
+
+    /// Date moved to the trash (`None` if not deleted)
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub deleted_at: Option<OffsetDateTime>,
+
+    /// Expiration date
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub expires_at: Option<OffsetDateTime>,
+
+    /// Maximum count of views of original media
+    pub max_views: Option<i32>,
+
+    /// Count of views of original media
+    pub view_count: i32,
+
+    /// SHA-256 hash of uploaded data (hex encoded)
+    pub content_hash: Option<String>,
+
+    /// ID of stored files, shared between media with the same content
+    pub storage_id: String,
+
+    /// Perceptual hash (dHash) of the image
+    pub perceptual_hash: Option<i64>,
+
+    /// Camera make and model from EXIF
+    pub exif_camera: Option<String>,
+
+    /// Lens model from EXIF
+    pub exif_lens: Option<String>,
+
+    /// Captured date from EXIF (timezone unknown)
+    pub exif_captured_at: Option<PrimitiveDateTime>,
+
+    /// Orientation tag value from EXIF
+    /// Stored images are already rotated, so this is informative only.
+    pub exif_orientation: Option<i16>,
+
+    /// BlurHash string for placeholder
+    pub blurhash: Option<String>,
+
+    /// Dominant color in `#rrggbb` form
+    pub dominant_color: Option<String>,
+
+    /// Vanity slug used in URLs instead of hash ID
+    pub slug: Option<String>,
+
+    /// Extensions of derivatives served by content negotiation (`None` if not generated yet)
+    pub variants: Option<Vec<String>>,
 }
 
 #[allow(dead_code)]
 impl Media {
+    /// Returns the ID used in URLs; the slug if set, otherwise the hash ID.
+    pub fn public_id(&self) -> &str {
+        self.slug.as_deref().unwrap_or(&self.hash_id)
+    }
+
+    /// Returns the filename of the original media in the storage.
+    pub fn original_filename(&self) -> String {
+        format!("{}.{}", self.storage_id, self.extension)
+    }
+
+    /// Returns the filename of the thumbnail in the storage.
+    pub fn thumbnail_filename(&self) -> String {
+        format!("thumbnails/{}.jpg", self.storage_id)
+    }
+
+    /// Returns the filename of the derivative in the storage.
+    pub fn variant_filename(&self, extension: &str) -> String {
+        format!("variants/{}.{}", self.storage_id, extension)
+    }
+
+    /// Returns the extensions of available derivatives.
+    pub fn available_variants(&self) -> &[String] {
+        self.variants.as_deref().unwrap_or_default()
+    }
+
+    /// Returns CSS style for the placeholder shown until the image loads.
+    pub fn placeholder_style(&self) -> String {
+        match &self.dominant_color {
+            Some(color) => format!("background-color: {};", color),
+            None => String::new(),
+        }
+    }
+
+    /// Returns human-readable description of EXIF orientation.
+    pub fn orientation_str(&self) -> Option<&'static str> {
+        let description = match self.exif_orientation? {
+            1 => "Normal",
+            2 => "Mirrored horizontally",
+            3 => "Rotated 180°",
+            4 => "Mirrored vertically",
+            5 => "Mirrored horizontally, rotated 270° clockwise",
+            6 => "Rotated 90° clockwise",
+            7 => "Mirrored horizontally, rotated 90° clockwise",
+            8 => "Rotated 270° clockwise",
+            _ => "Unknown",
+        };
+        Some(description)
+    }
+
+    /// Judges whether this media has expired by date or view count.
+    pub fn is_expired(&self, now: OffsetDateTime) -> bool {
+        let by_date = self.expires_at.map(|e| e <= now).unwrap_or(false);
+        let by_views = self.max_views.map(|m| self.view_count >= m).unwrap_or(false);
+        by_date || by_views
+    }
+
     /// Returns approximate representation of filesize.
     pub fn filesize_str(&self) -> String {
         if self.filesize <= 1 {
@@ -47,3 +154,113 @@ impl Media {
         }
     }
 }
+
+#[cfg(test)]
+impl Media {
+    /// Constructs a public media without optional information.
+    pub fn fixture(hash_id: &str) -> Media {
+        Media {
+            hash_id: hash_id.to_string(),
+            extension: "png".to_string(),
+            has_thumbnail: false,
+            is_private: false,
+            width: 640,
+            height: 480,
+            filesize: 1024,
+            comment: None,
+            uploaded: OffsetDateTime::UNIX_EPOCH,
+            deleted_at: None,
+            expires_at: None,
+            max_views: None,
+            view_count: 0,
+            content_hash: None,
+            storage_id: hash_id.to_string(),
+            perceptual_hash: None,
+            exif_camera: None,
+            exif_lens: None,
+            exif_captured_at: None,
+            exif_orientation: None,
+            blurhash: None,
+            dominant_color: None,
+            slug: None,
+            variants: None,
+        }
+    }
+}
+
+/// Represents a webhook endpoint.
+#[derive(Debug, Clone, FromRow)]
+pub struct Webhook {
+    /// Webhook ID
+    pub id: i64,
+
+    /// Destination URL
+    pub url: String,
+
+    /// Secret key for HMAC signatures
+    pub secret: String,
+
+    /// Names of subscribed events
+    pub events: Vec<String>,
+
+    /// Whether events are delivered
+    pub is_enabled: bool,
+
+    /// Created date
+    pub created_at: OffsetDateTime,
+}
+
+impl Webhook {
+    /// Whether the webhook subscribes the event.
+    pub fn subscribes(&self, event: &str) -> bool {
+        self.events.iter().any(|e| e == event)
+    }
+}
+
+/// Represents a delivery of an event to a webhook.
+#[derive(Debug, Clone, FromRow)]
+pub struct WebhookDelivery {
+    /// Delivery ID
+    pub id: i64,
+
+    /// Destination webhook ID
+    pub webhook_id: i64,
+
+    /// Event name
+    pub event: String,
+
+    /// JSON payload
+    pub payload: String,
+
+    /// One of `pending`, `succeeded` and `failed`
+    pub status: String,
+
+    /// Number of attempts so far
+    pub attempts: i32,
+
+    /// Date of the next attempt
+    pub next_attempt_at: OffsetDateTime,
+
+    /// HTTP status of the last response
+    pub response_status: Option<i32>,
+
+    /// Error of the last attempt
+    pub last_error: Option<String>,
+
+    /// Created date
+    pub created_at: OffsetDateTime,
+
+    /// Date delivered successfully
+    pub delivered_at: Option<OffsetDateTime>,
+}
+
+impl WebhookDelivery {
+    /// Returns the result of the last attempt for display.
+    pub fn result_str(&self) -> String {
+        match (self.response_status, &self.last_error) {
+            (Some(status), _) => format!("HTTP {}", status),
+            (None, Some(error)) => error.clone(),
+            (None, None) => "-".to_string(),
+        }
+    }
+}
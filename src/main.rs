@@ -1,14 +1,17 @@
 mod action;
 mod api;
 mod application;
+mod command;
 mod entity;
+mod metrics;
 mod middleware;
+mod task;
 mod web;
 
 use crate::{
     api::ApiAuthorizationMiddleware,
     application::{Arguments, Environments, State, SubCommand},
-    middleware::{log_inner_error, GracefulShutdownMiddleware},
+    middleware::{log_inner_error, GracefulShutdownMiddleware, MetricsMiddleware},
     web::{deform_http_method, session::RedisStore, CsrfProtectionMiddleware, FormPreparseMiddleware},
 };
 
@@ -19,30 +22,57 @@ use argon2::{
     password_hash::{PasswordHasher, SaltString},
     Argon2,
 };
-use async_ctrlc::CtrlC;
-use async_std::prelude::*;
+use async_std::{future::timeout, prelude::*, task::spawn};
 use clap::Parser;
 use flexi_logger::Logger;
-use log::debug;
+use log::{debug, error, info, warn};
 use rand::prelude::*;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook_async_std::Signals;
 use tide::{
     http::cookies::SameSite,
+    listener::Listener,
     security::CorsMiddleware,
     sessions::SessionMiddleware,
     utils::{After, Before},
 };
 
+/// Time limit for closing each of database and Redis connections on shutdown.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[async_std::main]
 async fn main() -> Result<()> {
     dotenv::dotenv().ok();
     Logger::try_with_env()?.start()?;
 
-    let envs: Environments = envy::from_env()?;
     let args = Arguments::parse();
+    let envs = Environments::load(args.config.as_deref())?;
 
     match args.subcommand {
         Some(SubCommand::Serve) => run_server(envs).await?,
         Some(SubCommand::GeneratePassword) => generate_password().await?,
+        Some(SubCommand::CheckConfig) => command::config::check_config(&envs, args.config.as_deref())?,
+        Some(SubCommand::RegenerateThumbnails { batch_size, parallelism }) => {
+            command::regenerate::regenerate_thumbnails(&envs, batch_size, parallelism).await?
+        }
+        Some(SubCommand::Import {
+            directory,
+            use_mtime,
+            private,
+            journal,
+            dry_run,
+        }) => {
+            let options = command::import::ImportOptions {
+                use_mtime,
+                private,
+                journal: journal.map(Into::into),
+                dry_run,
+            };
+            command::import::import(&envs, directory, &options).await?
+        }
+        Some(SubCommand::Export { output }) => command::archive::export(&envs, output).await?,
+        Some(SubCommand::Restore { archive }) => command::archive::restore(&envs, archive).await?,
+        Some(SubCommand::Fsck { repair }) => command::fsck::fsck(&envs, repair).await?,
         None => run_server(envs).await?,
     }
 
@@ -57,15 +87,15 @@ async fn run_server(envs: Environments) -> Result<()> {
     // Web Routes -------------------------------------------------------------
     // To enable HTTP method deformation,
     // we have to split route server and nest it at root.
+    let session_store = RedisStore::new(&envs.redis_uri).await?;
     let mut web_routes = tide::with_state(state.clone());
     web_routes.with({
-        let store = RedisStore::new(&envs.redis_uri).await?;
-        let middleware = SessionMiddleware::new(store, &secret_key)
-            .with_session_ttl(Some(Duration::from_secs(86400 * 7)))
+        let middleware = SessionMiddleware::new(session_store.clone(), &secret_key)
+            .with_session_ttl(Some(Duration::from_secs(86400 * envs.session_ttl_days)))
             .with_same_site_policy(SameSite::Lax);
         middleware
     });
-    web_routes.with(CsrfProtectionMiddleware::new(state.cipher.clone()));
+    web_routes.with(CsrfProtectionMiddleware::new(state.cipher.clone(), state.token_expiary));
 
     // Root
     web_routes.at("/").get(web::endpoint::index);
@@ -88,19 +118,38 @@ async fn run_server(envs: Environments) -> Result<()> {
         .patch(web::endpoint::media::update)
         .delete(web::endpoint::media::delete);
 
+    // Trash
+    web_routes.at("/trash/").get(web::endpoint::trash::list_trash);
+    web_routes
+        .at("/trash/:hash_id")
+        .patch(web::endpoint::trash::restore)
+        .delete(web::endpoint::trash::purge);
+
+    // Webhooks
+    web_routes
+        .at("/webhooks/")
+        .get(web::endpoint::webhook::list_webhooks)
+        .post(web::endpoint::webhook::create);
+    web_routes
+        .at("/webhooks/:id")
+        .patch(web::endpoint::webhook::update)
+        .delete(web::endpoint::webhook::delete);
+
     // API Routes -------------------------------------------------------------
     let mut api_routes = tide::with_state(state.clone());
-    api_routes.with(ApiAuthorizationMiddleware::new(&envs.api_token));
+    api_routes.with(ApiAuthorizationMiddleware::new(&envs.api_token, state.metrics.clone()));
 
     api_routes.at("/show").get(api::endpoint::show);
+    api_routes.at("/similar").get(api::endpoint::similar);
     api_routes.at("/upload").post(api::endpoint::upload);
 
     // Root App --------------------------------------------------------------
-    let mut app = tide::new();
+    let mut app = tide::with_state(state.clone());
 
     // Middlewares
     let graceful_shutdown = GracefulShutdownMiddleware::new();
     app.with(graceful_shutdown.clone());
+    app.with(MetricsMiddleware::new(state.metrics.clone()));
     app.with(After(log_inner_error));
     app.with(CorsMiddleware::new());
     app.with(FormPreparseMiddleware);
@@ -110,17 +159,85 @@ async fn run_server(envs: Environments) -> Result<()> {
     app.at("/").nest(web_routes);
     app.at("/api").nest(api_routes);
     app.at("/public").serve_dir(&envs.public_dir)?;
-    app.at("/media").serve_dir(&envs.media_dir)?;
+    app.at("/media/*path").get(web::endpoint::media::serve);
+    app.at("/oembed").get(web::endpoint::oembed::oembed);
+    app.at("/healthz").get(web::endpoint::health::healthz);
+    app.at("/readyz").get({
+        let session_store = session_store.clone();
+        let graceful_shutdown = graceful_shutdown.clone();
+        move |request| web::endpoint::health::readyz(request, session_store.clone(), graceful_shutdown.clone())
+    });
+    app.at("/feed.atom").get(web::endpoint::feed::atom);
+    app.at("/feed.rss").get(web::endpoint::feed::rss);
+
+    // Metrics are served on a separate address if configured,
+    // otherwise only when protected by the token
+    match envs.metrics_listen_at.clone() {
+        Some(metrics_listen_at) => {
+            let mut metrics_app = tide::with_state(state.clone());
+            metrics_app.at("/metrics").get(web::endpoint::metrics::metrics);
+            spawn(async move {
+                if let Err(e) = metrics_app.listen(metrics_listen_at).await {
+                    error!("Metrics server stopped: {}", e);
+                }
+            });
+        }
+        None if envs.metrics_token.is_some() => {
+            app.at("/metrics").get(web::endpoint::metrics::metrics);
+        }
+        None => (),
+    }
+
+    // Background tasks
+    spawn(task::purge_trash(state.clone()));
+    spawn(task::purge_expired(state.clone()));
+    spawn(task::clean_staging(state.clone()));
+    spawn(task::generate_pending_variants(state.clone()));
+    spawn(task::deliver_webhooks(state.clone()));
 
     // Start server
-    let app_future = async { app.listen(envs.listen_at).await };
-    let shutdown_future = async {
-        let signal = CtrlC::new().expect("Cannot create signal");
-        signal.await;
-        graceful_shutdown.terminate().await;
-        Ok(())
+    let mut signals = Signals::new(&[SIGTERM, SIGINT])?;
+    let signals_handle = signals.handle();
+    let mut listener = app.bind(envs.listen_at.clone()).await?;
+    for listener_info in listener.info() {
+        info!("Server listening on {}", listener_info);
+    }
+
+    // Dropping the accepting future stops accepting new connections,
+    // while connections already accepted keep running in their own tasks
+    let accepting = async { listener.accept().await.map(|_| None) };
+    let signaled = async { Ok::<_, std::io::Error>(signals.next().await) };
+    match accepting.race(signaled).await? {
+        Some(signal) => info!("Received signal {}, shutting down", signal),
+        None => return Ok(()),
+    }
+    // Release the address so that a new instance can bind it while draining
+    drop(listener);
+
+    // Wait for requests in process, until the deadline or another signal
+    graceful_shutdown.terminate().await;
+    let drained = async { Some(graceful_shutdown.drain(Duration::from_secs(envs.shutdown_timeout)).await) };
+    let forced = async {
+        signals.next().await;
+        None
     };
-    app_future.race(shutdown_future).await?;
+    match drained.race(forced).await {
+        Some(0) => info!("All requests finished"),
+        Some(remaining) => warn!("Shutdown deadline exceeded with {} request(s) in process", remaining),
+        None => warn!("Shutdown forced by another signal"),
+    }
+    signals_handle.close();
+
+    // Sessions and readiness checks hold clones of the store, so the connection is closed explicitly
+    match timeout(CLOSE_TIMEOUT, session_store.close()).await {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => warn!("Failed to close Redis connection: {}", e),
+        Err(_) => warn!("Timed out closing Redis connection"),
+    }
+    if timeout(CLOSE_TIMEOUT, state.pool.close()).await.is_err() {
+        warn!("Timed out closing database connections");
+    }
+    info!("Closed connections, exiting");
 
     Ok(())
 }
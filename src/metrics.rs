@@ -0,0 +1,206 @@
+//! Contains Prometheus metrics.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use sqlx::PgPool;
+
+/// Bucket bounds of request durations in seconds.
+const DURATION_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Bucket bounds of upload sizes in bytes.
+const SIZE_BUCKETS: &[f64] = &[16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0, 67108864.0];
+
+/// Collects application metrics.
+/// Counters are kept in memory and reset on restart.
+#[derive(Debug)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    request_duration: Histogram,
+    upload_size: Histogram,
+    decode_duration: Histogram,
+    uploads: Mutex<BTreeMap<String, u64>>,
+    stored_bytes: AtomicU64,
+    thumbnails: AtomicU64,
+    auth_failures: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Metrics {
+    /// Constructs empty metrics.
+    pub fn new() -> Metrics {
+        Metrics {
+            requests: Mutex::new(BTreeMap::new()),
+            request_duration: Histogram::new(DURATION_BUCKETS),
+            upload_size: Histogram::new(SIZE_BUCKETS),
+            decode_duration: Histogram::new(DURATION_BUCKETS),
+            uploads: Mutex::new(BTreeMap::new()),
+            stored_bytes: AtomicU64::new(0),
+            thumbnails: AtomicU64::new(0),
+            auth_failures: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Records a handled HTTP request.
+    pub fn observe_request(&self, method: &str, status: u16, elapsed: Duration) {
+        *self
+            .requests
+            .lock()
+            .expect("Poisoned")
+            .entry((method.to_string(), status))
+            .or_default() += 1;
+        self.request_duration.observe(elapsed.as_secs_f64());
+    }
+
+    /// Records time spent on decoding an uploaded image.
+    pub fn observe_decode(&self, elapsed: Duration) {
+        self.decode_duration.observe(elapsed.as_secs_f64());
+    }
+
+    /// Records a created media.
+    pub fn record_upload(&self, extension: &str, filesize: u64) {
+        *self.uploads.lock().expect("Poisoned").entry(extension.to_string()).or_default() += 1;
+        self.upload_size.observe(filesize as f64);
+    }
+
+    /// Records newly written files.
+    pub fn record_stored(&self, bytes: u64, has_thumbnail: bool) {
+        self.stored_bytes.fetch_add(bytes, Ordering::Relaxed);
+        if has_thumbnail {
+            self.thumbnails.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records an authentication failure; `kind` is `signin`, `api` or `metrics`.
+    pub fn record_auth_failure(&self, kind: &'static str) {
+        *self.auth_failures.lock().expect("Poisoned").entry(kind).or_default() += 1;
+    }
+
+    /// Renders all metrics in Prometheus text format.
+    pub fn render(&self, pool: &PgPool) -> String {
+        let mut output = String::new();
+
+        header(&mut output, "kebisafe_http_requests_total", "counter", "Handled HTTP requests.");
+        for ((method, status), count) in self.requests.lock().expect("Poisoned").iter() {
+            let _ = writeln!(
+                output,
+                "kebisafe_http_requests_total{{method=\"{}\",status=\"{}\"}} {}",
+                escape_label(method),
+                status,
+                count
+            );
+        }
+        self.request_duration.render(
+            &mut output,
+            "kebisafe_http_request_duration_seconds",
+            "Time spent on handling HTTP requests.",
+        );
+
+        header(&mut output, "kebisafe_uploads_total", "counter", "Created media by format.");
+        for (format, count) in self.uploads.lock().expect("Poisoned").iter() {
+            let _ = writeln!(output, "kebisafe_uploads_total{{format=\"{}\"}} {}", escape_label(format), count);
+        }
+        self.upload_size
+            .render(&mut output, "kebisafe_upload_size_bytes", "Sizes of created media.");
+        self.decode_duration.render(
+            &mut output,
+            "kebisafe_image_decode_seconds",
+            "Time spent on decoding uploaded images.",
+        );
+
+        header(
+            &mut output,
+            "kebisafe_stored_bytes_total",
+            "counter",
+            "Bytes of newly stored original files.",
+        );
+        let _ = writeln!(output, "kebisafe_stored_bytes_total {}", self.stored_bytes.load(Ordering::Relaxed));
+        header(
+            &mut output,
+            "kebisafe_thumbnails_generated_total",
+            "counter",
+            "Thumbnails generated for uploaded media.",
+        );
+        let _ = writeln!(
+            output,
+            "kebisafe_thumbnails_generated_total {}",
+            self.thumbnails.load(Ordering::Relaxed)
+        );
+
+        header(&mut output, "kebisafe_auth_failures_total", "counter", "Failed authentications.");
+        for (kind, count) in self.auth_failures.lock().expect("Poisoned").iter() {
+            let _ = writeln!(output, "kebisafe_auth_failures_total{{kind=\"{}\"}} {}", kind, count);
+        }
+
+        header(&mut output, "kebisafe_db_connections", "gauge", "Connections in the database pool.");
+        let _ = writeln!(output, "kebisafe_db_connections{{state=\"total\"}} {}", pool.size());
+        let _ = writeln!(output, "kebisafe_db_connections{{state=\"idle\"}} {}", pool.num_idle());
+
+        output
+    }
+}
+
+/// Cumulative histogram.
+#[derive(Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    data: Mutex<HistogramData>,
+}
+
+#[derive(Debug, Default)]
+struct HistogramData {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            data: Mutex::new(HistogramData {
+                counts: vec![0; bounds.len()],
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        let mut data = self.data.lock().expect("Poisoned");
+        for (bound, count) in self.bounds.iter().zip(data.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        data.sum += value;
+        data.count += 1;
+    }
+
+    fn render(&self, output: &mut String, name: &str, help: &str) {
+        let data = self.data.lock().expect("Poisoned");
+        header(output, name, "histogram", help);
+        for (bound, count) in self.bounds.iter().zip(data.counts.iter()) {
+            let _ = writeln!(output, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(output, "{}_bucket{{le=\"+Inf\"}} {}", name, data.count);
+        let _ = writeln!(output, "{}_sum {}", name, data.sum);
+        let _ = writeln!(output, "{}_count {}", name, data.count);
+    }
+}
+
+/// Writes `HELP` and `TYPE` lines.
+fn header(output: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} {}", name, kind);
+}
+
+/// Escapes a label value.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
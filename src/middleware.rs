@@ -1,15 +1,23 @@
 //! Contains tide middlewares.
 
-use async_std::sync::{Arc, RwLock};
+use crate::metrics::Metrics;
+
+use async_std::{
+    io::{self, BufRead, Read},
+    sync::{Arc, RwLock},
+    task::sleep,
+};
 use std::{
-    process::exit,
+    pin::Pin,
     sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
 use anyhow::{bail, Result};
 use async_trait::async_trait;
 use log::{error, info};
-use tide::{Middleware, Next, Request, Response, Result as TideResult};
+use tide::{http::StatusCode, Body, Middleware, Next, Request, Response, Result as TideResult};
 
 /// Records client error logs.
 pub async fn log_inner_error(response: Response) -> TideResult {
@@ -19,27 +27,97 @@ pub async fn log_inner_error(response: Response) -> TideResult {
     Ok(response)
 }
 
-/// Performs graceful shutdown.
-#[derive(Debug, Clone)]
+/// Counts a request in process while alive.
+#[derive(Debug)]
 struct GracefulShutdownBox {
-    terminating: Arc<AtomicBool>,
     in_process: Arc<AtomicU32>,
 }
 
+impl Clone for GracefulShutdownBox {
+    fn clone(&self) -> GracefulShutdownBox {
+        self.in_process.fetch_add(1, Ordering::AcqRel);
+        GracefulShutdownBox {
+            in_process: self.in_process.clone(),
+        }
+    }
+}
+
 impl Drop for GracefulShutdownBox {
     fn drop(&mut self) {
-        let previous_count = self.in_process.fetch_sub(1, Ordering::Release);
-        let should_exit = self.terminating.load(Ordering::Acquire);
-        if previous_count == 1 && should_exit {
-            exit(0);
+        self.in_process.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Response body which keeps the request counted until it is read to the end or dropped.
+/// Files are streamed after the handler returns, so the handler alone doesn't cover the whole request.
+#[derive(Debug)]
+struct ReservedBody {
+    body: Body,
+    remaining: Option<usize>,
+    reserved: Option<GracefulShutdownBox>,
+}
+
+impl ReservedBody {
+    /// Wraps the body. Bodies with known length are never polled past it, so the end is detected by the length.
+    fn new(body: Body, reserved: GracefulShutdownBox) -> ReservedBody {
+        ReservedBody {
+            remaining: body.len(),
+            body,
+            reserved: Some(reserved),
+        }
+    }
+
+    /// Counts bytes sent, and releases the request at the end.
+    fn advance(&mut self, amount: usize, eof: bool) {
+        self.remaining = self.remaining.map(|r| r.saturating_sub(amount));
+        if eof || self.remaining == Some(0) {
+            self.reserved = None;
+        }
+    }
+}
+
+impl Read for ReservedBody {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let read = Pin::new(&mut self.body).poll_read(cx, buf);
+        if let Poll::Ready(Ok(amount)) = read {
+            self.advance(amount, amount == 0 && !buf.is_empty());
+        }
+        read
+    }
+}
+
+impl BufRead for ReservedBody {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.body).poll_fill_buf(cx) {
+            Poll::Ready(Ok(buf)) => {
+                if buf.is_empty() {
+                    this.reserved = None;
+                }
+                Poll::Ready(Ok(buf))
+            }
+            other => other,
         }
     }
+
+    fn consume(mut self: Pin<&mut Self>, amount: usize) {
+        Pin::new(&mut self.body).consume(amount);
+        self.advance(amount, false);
+    }
 }
 
+/// Paths of health probes, which are served even while terminating.
+const PROBE_PATHS: &[&str] = &["/healthz", "/readyz"];
+
+/// Interval of checking in-process requests while draining.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 /// Deals with graceful shutdown.
 #[derive(Debug, Clone)]
 pub struct GracefulShutdownMiddleware {
     shutdown_box: Arc<RwLock<Option<GracefulShutdownBox>>>,
+    terminating: Arc<AtomicBool>,
+    in_process: Arc<AtomicU32>,
 }
 
 impl GracefulShutdownMiddleware {
@@ -47,34 +125,51 @@ impl GracefulShutdownMiddleware {
     pub fn new() -> GracefulShutdownMiddleware {
         let terminating = Arc::new(AtomicBool::new(false));
         let in_process = Arc::new(AtomicU32::new(1));
-        let shutdown_box = Arc::new(RwLock::new(Some(GracefulShutdownBox { terminating, in_process })));
+        let shutdown_box = Arc::new(RwLock::new(Some(GracefulShutdownBox {
+            in_process: in_process.clone(),
+        })));
 
-        GracefulShutdownMiddleware { shutdown_box }
+        GracefulShutdownMiddleware {
+            shutdown_box,
+            terminating,
+            in_process,
+        }
+    }
+
+    /// Whether termination has been ordered.
+    pub fn is_terminating(&self) -> bool {
+        self.terminating.load(Ordering::Acquire)
     }
 
     /// Reserves a new Box if not terminating.
     async fn reserve(&self) -> Result<GracefulShutdownBox> {
         let locked = self.shutdown_box.read().await;
         match locked.as_ref() {
-            Some(sb) => {
-                let reserved_box = sb.clone();
-                reserved_box.in_process.fetch_add(1, Ordering::Acquire);
-                Ok(reserved_box)
-            }
+            Some(sb) => Ok(sb.clone()),
             None => bail!("Already started to terminate"),
         }
     }
 
     /// Starts to terminate.
+    /// New requests are rejected after this.
     pub async fn terminate(&self) {
         let mut locked = self.shutdown_box.write().await;
-        match locked.as_mut() {
-            Some(sb) => {
-                sb.terminating.store(true, Ordering::Release);
-                let _ = locked.take();
-                info!("Termination ordered");
+        if locked.take().is_some() {
+            self.terminating.store(true, Ordering::Release);
+            info!("Termination ordered");
+        }
+    }
+
+    /// Waits until all requests in process finish, up to `deadline`.
+    /// Returns the number of requests still in process.
+    pub async fn drain(&self, deadline: Duration) -> u32 {
+        let started = Instant::now();
+        loop {
+            let in_process = self.in_process.load(Ordering::Acquire);
+            if in_process == 0 || started.elapsed() >= deadline {
+                return in_process;
             }
-            None => (),
+            sleep(DRAIN_POLL_INTERVAL).await;
         }
     }
 }
@@ -82,8 +177,54 @@ impl GracefulShutdownMiddleware {
 #[async_trait]
 impl<State: 'static + Send + Sync + Clone> Middleware<State> for GracefulShutdownMiddleware {
     async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> TideResult {
-        let _reserved = self.reserve().await?;
+        // Probes don't delay termination, and keep reporting while terminating
+        if PROBE_PATHS.contains(&request.url().path()) {
+            return Ok(next.run(request).await);
+        }
+
+        let reserved = match self.reserve().await {
+            Ok(reserved) => reserved,
+            Err(_) => {
+                return Ok(Response::builder(StatusCode::ServiceUnavailable)
+                    .header("Connection", "close")
+                    .body("Server is shutting down")
+                    .build())
+            }
+        };
+        let mut response = next.run(request).await;
+
+        // The body is swapped rather than replaced, which would add Content-Type to responses without one
+        let mut body = Body::empty();
+        response.swap_body(&mut body);
+        let (length, mime) = (body.len(), body.mime().clone());
+        let mut reserved_body = Body::from_reader(ReservedBody::new(body, reserved), length);
+        reserved_body.set_mime(mime);
+        response.swap_body(&mut reserved_body);
+
+        Ok(response)
+    }
+}
+
+/// Records counts and durations of requests.
+#[derive(Debug, Clone)]
+pub struct MetricsMiddleware {
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsMiddleware {
+    /// Constructs a new middleware.
+    pub fn new(metrics: Arc<Metrics>) -> MetricsMiddleware {
+        MetricsMiddleware { metrics }
+    }
+}
+
+#[async_trait]
+impl<State: 'static + Send + Sync + Clone> Middleware<State> for MetricsMiddleware {
+    async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> TideResult {
+        let method = request.method().to_string();
+        let started = Instant::now();
         let response = next.run(request).await;
+        self.metrics.observe_request(&method, response.status() as u16, started.elapsed());
 
         Ok(response)
     }
@@ -0,0 +1,196 @@
+//! Contains background tasks.
+
+use crate::{
+    action::{
+        database::{
+            fetch_due_webhook_deliveries, fetch_expired_media_list, fetch_media_pending_variants, fetch_purgeable_media_list, fetch_webhooks,
+            insert_media_tombstones, remove_media_tombstones, update_media_variants,
+        },
+        media::{generate_variants, list_staging_leftovers, purge_media, STAGING_LEFTOVER_AGE},
+        webhook::{abandon, deliver, notify, WebhookEvent, STATUS_SUCCEEDED},
+    },
+    application::State,
+};
+
+use async_std::{fs, sync::Arc, task::sleep};
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{error, info, warn};
+use time::{Duration as TimeDuration, OffsetDateTime};
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+const STAGING_INTERVAL: Duration = Duration::from_secs(3600);
+const EXPIRATION_INTERVAL: Duration = Duration::from_secs(60);
+const VARIANTS_INTERVAL: Duration = Duration::from_secs(30);
+const VARIANTS_BATCH_SIZE: usize = 10;
+const WEBHOOK_INTERVAL: Duration = Duration::from_secs(10);
+const WEBHOOK_BATCH_SIZE: usize = 20;
+
+/// Period to keep telling that purged media have expired, instead of not found.
+const TOMBSTONE_RETENTION: TimeDuration = TimeDuration::days(30);
+
+/// Periodically purges media which stayed in the trash longer than the retention period.
+pub async fn purge_trash(state: Arc<State>) {
+    loop {
+        if let Err(e) = purge_trash_once(&state).await {
+            error!("Failed to purge trash: {}", e);
+        }
+        sleep(PURGE_INTERVAL).await;
+    }
+}
+
+async fn purge_trash_once(state: &State) -> Result<()> {
+    let deleted_before = OffsetDateTime::now_local()? - state.trash_retention;
+    let media_list = fetch_purgeable_media_list(&state.pool, deleted_before).await?;
+
+    let mut purged = 0;
+    for media in &media_list {
+        // A failure of one media shouldn't block purging the others
+        match purge_media(&state.pool, &state.media_root, media).await {
+            Ok(()) => {
+                notify(state, WebhookEvent::Deleted, media).await;
+                purged += 1;
+            }
+            Err(e) => error!("Failed to purge {}: {}", media.hash_id, e),
+        }
+    }
+
+    if purged > 0 {
+        info!("Purged {} media from the trash", purged);
+    }
+    Ok(())
+}
+
+/// Periodically purges media which have expired by date or view count.
+pub async fn purge_expired(state: Arc<State>) {
+    loop {
+        if let Err(e) = purge_expired_once(&state).await {
+            error!("Failed to purge expired media: {}", e);
+        }
+        sleep(EXPIRATION_INTERVAL).await;
+    }
+}
+
+async fn purge_expired_once(state: &State) -> Result<()> {
+    let now = OffsetDateTime::now_local()?;
+    let media_list = fetch_expired_media_list(&state.pool, now).await?;
+
+    let mut purged = 0;
+    for media in &media_list {
+        let result = match insert_media_tombstones(&state.pool, media, now).await {
+            Ok(()) => purge_media(&state.pool, &state.media_root, media).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {
+                notify(state, WebhookEvent::Deleted, media).await;
+                purged += 1;
+            }
+            Err(e) => error!("Failed to purge {}: {}", media.hash_id, e),
+        }
+    }
+
+    if purged > 0 {
+        info!("Purged {} expired media", purged);
+    }
+
+    let removed = remove_media_tombstones(&state.pool, now - TOMBSTONE_RETENTION).await?;
+    if removed > 0 {
+        info!("Removed {} tombstone(s) of expired media", removed);
+    }
+    Ok(())
+}
+
+/// Periodically removes staged files left by uploads interrupted by a crash.
+pub async fn clean_staging(state: Arc<State>) {
+    loop {
+        if let Err(e) = clean_staging_once(&state).await {
+            error!("Failed to clean staging directory: {}", e);
+        }
+        sleep(STAGING_INTERVAL).await;
+    }
+}
+
+async fn clean_staging_once(state: &State) -> Result<()> {
+    let leftovers = list_staging_leftovers(&state.media_root, STAGING_LEFTOVER_AGE).await?;
+
+    for path in &leftovers {
+        fs::remove_file(path).await?;
+    }
+
+    if !leftovers.is_empty() {
+        info!("Removed {} leftover staged file(s)", leftovers.len());
+    }
+    Ok(())
+}
+
+/// Periodically generates WebP/AVIF derivatives of media which don't have them yet.
+pub async fn generate_pending_variants(state: Arc<State>) {
+    loop {
+        if let Err(e) = generate_pending_variants_once(&state).await {
+            error!("Failed to generate variants: {}", e);
+        }
+        sleep(VARIANTS_INTERVAL).await;
+    }
+}
+
+async fn generate_pending_variants_once(state: &State) -> Result<()> {
+    let media_list = fetch_media_pending_variants(&state.pool, VARIANTS_BATCH_SIZE).await?;
+
+    for media in &media_list {
+        if let Err(e) = generate_variants(&state.pool, &state.media_root, media).await {
+            // Records no variants so that a broken file is not retried forever
+            error!("Failed to generate variants of {}: {}", media.hash_id, e);
+            update_media_variants(&state.pool, &media.storage_id, &[]).await?;
+        }
+    }
+
+    if !media_list.is_empty() {
+        info!("Generated variants of {} media", media_list.len());
+    }
+    Ok(())
+}
+
+/// Periodically delivers queued webhook events.
+pub async fn deliver_webhooks(state: Arc<State>) {
+    loop {
+        if let Err(e) = deliver_webhooks_once(&state).await {
+            error!("Failed to deliver webhooks: {}", e);
+        }
+        sleep(WEBHOOK_INTERVAL).await;
+    }
+}
+
+async fn deliver_webhooks_once(state: &State) -> Result<()> {
+    let deliveries = fetch_due_webhook_deliveries(&state.pool, WEBHOOK_BATCH_SIZE).await?;
+    if deliveries.is_empty() {
+        return Ok(());
+    }
+
+    let webhooks = fetch_webhooks(&state.pool).await?;
+    for delivery in deliveries {
+        let webhook = match webhooks.iter().find(|w| w.id == delivery.webhook_id) {
+            Some(w) if w.is_enabled => w,
+            _ => {
+                abandon(state, delivery, "Webhook is disabled").await?;
+                continue;
+            }
+        };
+
+        let delivery = deliver(state, webhook, delivery).await?;
+        if delivery.status == STATUS_SUCCEEDED {
+            info!("Delivered {} #{} to {}", delivery.event, delivery.id, webhook.url);
+        } else {
+            warn!(
+                "Failed to deliver {} #{} to {} (attempt {}): {}",
+                delivery.event,
+                delivery.id,
+                webhook.url,
+                delivery.attempts,
+                delivery.result_str()
+            );
+        }
+    }
+    Ok(())
+}
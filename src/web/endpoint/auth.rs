@@ -54,6 +54,7 @@ pub async fn signin(mut request: Request<Arc<State>>) -> TideResult {
 
     // Verify username
     if &params.username != &state.account.0 {
+        state.metrics.record_auth_failure("signin");
         flashes.push(Flash::Error("User not found".into()));
         swap_flashes(session, flashes)?;
         return Ok(Redirect::new("/signin").into());
@@ -65,6 +66,7 @@ pub async fn signin(mut request: Request<Arc<State>>) -> TideResult {
     match argon2.verify_password(params.password.as_bytes(), &password_hash) {
         Ok(()) => {}
         Err(_) => {
+            state.metrics.record_auth_failure("signin");
             flashes.push(Flash::Error("User not found".into()));
             swap_flashes(session, flashes)?;
             return Ok(Redirect::new("/signin").into());
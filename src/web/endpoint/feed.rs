@@ -0,0 +1,120 @@
+//! Contains feed endpoints.
+
+use crate::{
+    action::{database::fetch_media_list, media::thumbnail_dimensions},
+    application::State,
+    entity::Media,
+    web::{endpoint::media::original_content_type, template},
+};
+
+use async_std::sync::Arc;
+
+use anyhow::Result;
+use log::debug;
+use tide::{http::StatusCode, Request, Response, Result as TideResult};
+use time::{
+    format_description::well_known::{Rfc2822, Rfc3339},
+    OffsetDateTime, UtcOffset,
+};
+use yarte::Template;
+
+const FEED_TITLE: &str = "Kebisafe";
+const FEED_DESCRIPTION: &str = "Recently uploaded media";
+const FEED_CACHE_CONTROL: &str = "public, max-age=300";
+
+/// `GET /feed.atom`
+/// Atom feed of recent public media.
+pub async fn atom(request: Request<Arc<State>>) -> TideResult {
+    debug!("Rendering /feed.atom");
+
+    let state = request.state().clone();
+    let (entries, updated) = fetch_entries(&state).await?;
+    let info = template::PageInfo::new(&state, "/feed.atom")?
+        .with_title(FEED_TITLE)
+        .with_description(FEED_DESCRIPTION);
+    let body = template::AtomFeed {
+        info,
+        site_url: state.hosted_at.join("/m/")?.to_string(),
+        updated: updated.format(&Rfc3339)?,
+        entries,
+    }
+    .call()?;
+
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type("application/atom+xml; charset=utf-8")
+        .header("Cache-Control", FEED_CACHE_CONTROL)
+        .body(body)
+        .build())
+}
+
+/// `GET /feed.rss`
+/// RSS 2.0 feed of recent public media.
+pub async fn rss(request: Request<Arc<State>>) -> TideResult {
+    debug!("Rendering /feed.rss");
+
+    let state = request.state().clone();
+    let (entries, updated) = fetch_entries(&state).await?;
+    let info = template::PageInfo::new(&state, "/feed.rss")?
+        .with_title(FEED_TITLE)
+        .with_description(FEED_DESCRIPTION);
+    let body = template::RssFeed {
+        info,
+        site_url: state.hosted_at.join("/m/")?.to_string(),
+        updated: updated.format(&Rfc2822)?,
+        entries,
+    }
+    .call()?;
+
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type("application/rss+xml; charset=utf-8")
+        .header("Cache-Control", FEED_CACHE_CONTROL)
+        .body(body)
+        .build())
+}
+
+/// Fetches recent media and returns feed entries with the last updated time.
+/// `fetch_media_list` never returns private media.
+/// View-limited media are left out, since feed readers prefetch enclosures and would consume views.
+async fn fetch_entries(state: &State) -> Result<(Vec<template::FeedEntry>, OffsetDateTime)> {
+    let media_list = fetch_media_list(&state.pool, None, state.media_list_count).await?;
+    let updated = media_list.first().map(|m| m.uploaded).unwrap_or_else(OffsetDateTime::now_utc);
+    let entries = media_list
+        .iter()
+        .filter(|m| m.max_views.is_none())
+        .map(|m| feed_entry(state, m))
+        .collect::<Result<_>>()?;
+
+    Ok((entries, updated.to_offset(UtcOffset::UTC)))
+}
+
+/// Builds a feed entry from `Media`.
+fn feed_entry(state: &State, media: &Media) -> Result<template::FeedEntry> {
+    let public_id = media.public_id();
+    let original_url = state
+        .hosted_at
+        .join(&format!("/media/{}.{}", public_id, media.extension))?
+        .to_string();
+    let (thumbnail_url, thumbnail_size) = if media.has_thumbnail {
+        let size = thumbnail_dimensions(media.width as u32, media.height as u32, state.thumbnail_mode, state.thumbnail_size);
+        let url = state.hosted_at.join(&format!("/media/thumbnails/{}.jpg", public_id))?.to_string();
+        (url, size)
+    } else {
+        (original_url.clone(), (media.width as u32, media.height as u32))
+    };
+    let published = media.uploaded.to_offset(UtcOffset::UTC);
+
+    Ok(template::FeedEntry {
+        title: format!("Media #{}", media.hash_id),
+        summary: media.comment.clone(),
+        page_url: state.hosted_at.join(&format!("/m/{}", public_id))?.to_string(),
+        original_url,
+        original_type: original_content_type(media),
+        filesize: media.filesize,
+        width: media.width,
+        height: media.height,
+        thumbnail_url,
+        thumbnail_size,
+        published_rfc3339: published.format(&Rfc3339)?,
+        published_rfc2822: published.format(&Rfc2822)?,
+    })
+}
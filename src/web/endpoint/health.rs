@@ -0,0 +1,112 @@
+//! Contains health check endpoints.
+
+use crate::{action::media::STAGING_DIRECTORY, application::State, middleware::GracefulShutdownMiddleware, web::session::RedisStore};
+
+use async_std::{fs, future::timeout, sync::Arc};
+use std::{collections::BTreeMap, future::Future, time::Duration};
+
+use anyhow::Result;
+use rand::{distributions::Alphanumeric, prelude::*};
+use serde::Serialize;
+use tide::{
+    http::{mime, StatusCode},
+    Request, Response, Result as TideResult,
+};
+
+/// Time limit of each readiness check.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Result of a readiness check.
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckResult {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthResponse {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub checks: BTreeMap<&'static str, CheckResult>,
+}
+
+/// `GET /healthz`
+/// Reports that the process is alive.
+pub async fn healthz(_request: Request<Arc<State>>) -> TideResult {
+    let body = HealthResponse {
+        status: "ok",
+        checks: BTreeMap::new(),
+    };
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(mime::JSON)
+        .header("Cache-Control", "no-store")
+        .body(serde_json::to_string(&body)?)
+        .build())
+}
+
+/// `GET /readyz`
+/// Reports whether the server can handle requests.
+/// It checks the database, Redis and write access to the media directory, and fails once termination has been ordered.
+pub async fn readyz(request: Request<Arc<State>>, store: RedisStore, graceful_shutdown: GracefulShutdownMiddleware) -> TideResult {
+    let state = request.state().clone();
+
+    let mut checks = BTreeMap::new();
+    checks.insert(
+        "shutdown",
+        if graceful_shutdown.is_terminating() {
+            CheckResult {
+                ok: false,
+                error: Some("Terminating".into()),
+            }
+        } else {
+            CheckResult { ok: true, error: None }
+        },
+    );
+    checks.insert(
+        "database",
+        check(async { sqlx::query("SELECT 1;").execute(&state.pool).await.map(|_| ()).map_err(Into::into) }).await,
+    );
+    checks.insert("redis", check(store.ping()).await);
+    checks.insert("media_dir", check(check_media_dir(&state)).await);
+
+    let ready = checks.values().all(|c| c.ok);
+    let body = HealthResponse {
+        status: if ready { "ready" } else { "not_ready" },
+        checks,
+    };
+    Ok(
+        Response::builder(if ready { StatusCode::Ok } else { StatusCode::ServiceUnavailable })
+            .content_type(mime::JSON)
+            .header("Cache-Control", "no-store")
+            .body(serde_json::to_string(&body)?)
+            .build(),
+    )
+}
+
+/// Runs a check with the time limit.
+async fn check(future: impl Future<Output = Result<()>>) -> CheckResult {
+    match timeout(CHECK_TIMEOUT, future).await {
+        Ok(Ok(())) => CheckResult { ok: true, error: None },
+        Ok(Err(e)) => CheckResult {
+            ok: false,
+            error: Some(e.to_string()),
+        },
+        Err(_) => CheckResult {
+            ok: false,
+            error: Some("Timed out".into()),
+        },
+    }
+}
+
+/// Writes and removes a file in the staging directory.
+async fn check_media_dir(state: &State) -> Result<()> {
+    let staging_root = state.media_root.join(STAGING_DIRECTORY);
+    fs::create_dir_all(&staging_root).await?;
+
+    let probe_id: String = thread_rng().sample_iter(&Alphanumeric).take(16).map(char::from).collect();
+    let probe_path = staging_root.join(format!("readyz-{}", probe_id));
+    fs::write(&probe_path, b"").await?;
+    fs::remove_file(&probe_path).await?;
+    Ok(())
+}
@@ -2,32 +2,43 @@
 
 use crate::{
     action::{
-        database::{fetch_media, fetch_media_list, remove_media_record, reserve_media_record, update_media_record},
-        media::{create_thumbnail, save_image, validate_image_file},
+        database::{
+            consume_media_view, fetch_media, fetch_media_by_key, fetch_media_by_slug_alias, fetch_media_list, fetch_similar_media_list,
+            is_media_tombstoned, trash_media_record, update_media_record, update_media_slug, SlugTakenError,
+        },
+        media::{check_slug, store_media, thumbnail_dimensions, validate_image_file, StoredMedia, UploadOptions, VARIANT_FORMATS},
         session::{swap_flashes, Common, Flash},
+        webhook::{notify, WebhookEvent},
     },
     application::State,
     ensure_login,
     entity::Media,
     validate_form,
-    web::{multipart::MultipartData, template, RequestPreParseExt},
+    web::{
+        file::{Delivery, StaticFile},
+        multipart::MultipartData,
+        template, RequestPreParseExt,
+    },
 };
 
-use async_std::{fs, sync::Arc, task::spawn};
+use async_std::sync::Arc;
+use std::time::Instant;
 
-use anyhow::Result;
-use image::ImageFormat;
+use anyhow::{bail, Result};
 use log::debug;
 use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
 use tide::{
-    http::{mime, StatusCode},
+    http::{mime, Mime, StatusCode},
     sessions::Session,
     Redirect, Request, Response, Result as TideResult,
 };
+use time::{format_description::well_known::Rfc3339, macros::format_description, OffsetDateTime, PrimitiveDateTime, UtcOffset};
 use url::Url;
 use yarte::Template;
 
-const MEDIA_LIST_COUNT: usize = 50;
+const SIMILAR_THRESHOLD: u32 = 10;
+const SIMILAR_COUNT: usize = 6;
 
 /// `GET /m/`
 /// Shows a media.
@@ -39,7 +50,7 @@ pub async fn list_media(mut request: Request<Arc<State>>) -> TideResult {
 
     let info = template::PageInfo::new(&state, "/m/")?.with_title("Recently uploaded media");
     let common = Common::new(&state, session, vec![])?;
-    let media_list = fetch_media_list(&state.pool, None, MEDIA_LIST_COUNT).await?;
+    let media_list = fetch_media_list(&state.pool, None, state.media_list_count).await?;
     Ok(Response::builder(StatusCode::Ok)
         .content_type(mime::HTML)
         .body(template::MediaIndex { info, common, media_list }.call()?)
@@ -59,29 +70,82 @@ pub async fn media(mut request: Request<Arc<State>>) -> TideResult {
     let state = request.state().clone();
     let hash_id = request.param("hash_id").expect("hash_id must be set").to_string();
     let query: Parameters = request.query()?;
-    let session = request.session_mut();
 
-    let media_record = fetch_media(&state.pool, &hash_id).await?;
-    let response = if query.download.unwrap_or_default() {
-        media_download(state, media_record).await?
+    let media_record = fetch_media_by_key(&state.pool, &hash_id).await?;
+    if media_record.is_none() {
+        if let Some(moved) = fetch_media_by_slug_alias(&state.pool, &hash_id).await? {
+            let location = match request.url().query() {
+                Some(q) => format!("/m/{}?{}", moved.public_id(), q),
+                None => format!("/m/{}", moved.public_id()),
+            };
+            return Ok(Redirect::permanent(location).into());
+        }
+        if is_media_tombstoned(&state.pool, &hash_id).await? {
+            return Ok(media_expired(state, &hash_id, request.session_mut())?);
+        }
+    }
+
+    let now = OffsetDateTime::now_local()?;
+    let response = if media_record.as_ref().map(|m| m.is_expired(now)).unwrap_or_default() {
+        media_expired(state, &hash_id, request.session_mut())?
+    } else if query.download.unwrap_or_default() {
+        media_download(&request, state, media_record).await?
     } else {
-        media_page(state, media_record, session)?
+        media_page(state, media_record, request.session_mut()).await?
     };
     Ok(response)
 }
 
 /// Renders media page.
-fn media_page(state: Arc<State>, media: Option<Media>, session: &mut Session) -> Result<Response> {
+async fn media_page(state: Arc<State>, media: Option<Media>, session: &mut Session) -> Result<Response> {
     if let Some(media_record) = media {
         let common = Common::new(&state, session, vec![])?;
-        let info = template::PageInfo::new(&state, &format!("/m/{}", media_record.hash_id))?
-            .with_title(&format!("Media #{}", media_record.hash_id))
-            .with_description(media_record.comment.as_deref().unwrap_or("<No comment>"))
-            .with_thumbnail(&Url::parse(&common.permalink_thumbnail(&media_record))?);
+        let similar_list = fetch_similar_media_list(
+            &state.pool,
+            &media_record,
+            SIMILAR_THRESHOLD,
+            common.account.is_some(),
+            SIMILAR_COUNT,
+        )
+        .await?
+        .into_iter()
+        .map(|(m, _)| m)
+        .collect();
+        let mut info = template::PageInfo::new(&state, &format!("/m/{}", media_record.public_id()))?
+            .with_title(&format!("Media #{}", media_record.hash_id));
+        // Private media must not be previewed by link unfurlers and crawlers,
+        // and fetching previews of view-limited media would consume views
+        if !media_record.is_private && media_record.max_views.is_none() {
+            let (thumbnail_size, thumbnail_type) = if media_record.has_thumbnail {
+                let size = thumbnail_dimensions(
+                    media_record.width as u32,
+                    media_record.height as u32,
+                    state.thumbnail_mode,
+                    state.thumbnail_size,
+                );
+                (size, mime::JPEG.to_string())
+            } else {
+                (
+                    (media_record.width as u32, media_record.height as u32),
+                    original_content_type(&media_record),
+                )
+            };
+            info = info
+                .with_description(media_record.comment.as_deref().unwrap_or("<No comment>"))
+                .with_thumbnail(
+                    &Url::parse(&common.permalink_thumbnail(&media_record))?,
+                    thumbnail_size,
+                    &thumbnail_type,
+                )
+                .with_oembed(&state.hosted_at)?;
+            let image_object = image_object(&info.url, &common, &media_record)?;
+            info = info.with_structured_data(&image_object)?;
+        }
         let body = template::MediaShow {
             info,
             common,
             media: media_record,
+            similar_list,
         }
         .call()?;
         Ok(Response::builder(StatusCode::Ok).content_type(mime::HTML).body(body).build())
@@ -92,19 +156,68 @@ fn media_page(state: Arc<State>, media: Option<Media>, session: &mut Session) ->
     }
 }
 
+/// Builds schema.org `ImageObject` of the media.
+fn image_object(page_url: &Url, common: &Common, media: &Media) -> Result<JsonValue> {
+    let mut object = json!({
+        "@context": "https://schema.org",
+        "@type": "ImageObject",
+        "name": format!("Media #{}", media.hash_id),
+        "url": page_url.to_string(),
+        "contentUrl": common.permalink_original(media),
+        "thumbnailUrl": common.permalink_thumbnail(media),
+        "width": media.width,
+        "height": media.height,
+        "contentSize": media.filesize,
+        "encodingFormat": original_content_type(media),
+        "uploadDate": media.uploaded.format(&Rfc3339)?,
+    });
+    if let Some(comment) = &media.comment {
+        object["description"] = JsonValue::from(comment.as_str());
+    }
+    Ok(object)
+}
+
+/// Returns the media type of the original file.
+pub fn original_content_type(media: &Media) -> String {
+    Mime::from_extension(&media.extension)
+        .map(|m| m.essence().to_string())
+        .unwrap_or_else(|| "application/octet-stream".to_string())
+}
+
+/// Renders expired media page.
+fn media_expired(state: Arc<State>, hash_id: &str, session: &mut Session) -> Result<Response> {
+    let common = Common::new(&state, session, vec![])?;
+    let info = template::PageInfo::new(&state, &format!("/m/{}", hash_id))?.with_title("Media expired");
+    let body = template::MediaExpired {
+        info,
+        common,
+        hash_id: hash_id.to_string(),
+    }
+    .call()?;
+    Ok(Response::builder(StatusCode::Gone).content_type(mime::HTML).body(body).build())
+}
+
 /// Returns an attachment response.
-async fn media_download(state: Arc<State>, media: Option<Media>) -> Result<Response> {
-    if let Some(media_record) = media {
-        let filename = format!("{}.{}", media_record.hash_id, media_record.extension);
-        let path = state.media_root.join(&filename);
-        let file = fs::read(path).await?;
-        Ok(Response::builder(StatusCode::Ok)
-            .header("Content-Disposition", format!("attachment; filename=\"{}\"", filename))
-            .body(file)
-            .build())
-    } else {
-        Ok(Response::builder(StatusCode::NotFound).body("Media not found").build())
+async fn media_download(request: &Request<Arc<State>>, state: Arc<State>, media: Option<Media>) -> Result<Response> {
+    let media_record = match media {
+        Some(m) => m,
+        None => return Ok(Response::builder(StatusCode::NotFound).body("Media not found").build()),
+    };
+
+    let file = StaticFile::open(state.media_root.join(media_record.original_filename()), &media_record.storage_id).await?;
+    let delivery = evaluate_delivery(request, &file, &media_record, false);
+    if delivery.sends_beginning() && !consume_media_view(&state.pool, &media_record.hash_id).await? {
+        return Ok(Response::builder(StatusCode::Gone).body("Media expired").build());
     }
+
+    let filename = format!("{}.{}", media_record.public_id(), media_record.extension);
+    let requested_key = request.param("hash_id").unwrap_or_default();
+    let cache_control = cache_control(&media_record, requested_key, false);
+    let mut response = file
+        .respond(delivery, Mime::from_extension(&media_record.extension), cache_control)
+        .await?;
+    response.insert_header("Content-Disposition", format!("attachment; filename=\"{}\"", filename));
+    Ok(response)
 }
 
 /// POST `/upload`
@@ -119,6 +232,40 @@ pub async fn upload(mut request: Request<Arc<State>>) -> TideResult {
         .and_then(|v| v.as_str())
         .and_then(|v| v.parse().ok())
         .unwrap_or_default();
+    let strip_exif: bool = multipart
+        .get("strip_exif")
+        .and_then(|v| v.as_str())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_default();
+    let expires_at = match multipart.get("expires_at").and_then(|v| v.as_str()) {
+        Some(v) if !v.is_empty() => match parse_local_datetime(v) {
+            Ok(dt) => Some(dt),
+            Err(e) => {
+                let session = request.session_mut();
+                let flashes = vec![Flash::Error(format!("Invalid expiration date: {}", e))];
+                swap_flashes(session, flashes)?;
+                return Ok(Redirect::new("/").into());
+            }
+        },
+        _ => None,
+    };
+    let max_views = match multipart.get("max_views").and_then(|v| v.as_str()) {
+        Some(v) if !v.is_empty() => match v.parse::<i32>() {
+            Ok(v) if v > 0 => Some(v),
+            _ => {
+                let session = request.session_mut();
+                let flashes = vec![Flash::Error(format!("Invalid maximum views: {}", v))];
+                swap_flashes(session, flashes)?;
+                return Ok(Redirect::new("/").into());
+            }
+        },
+        _ => None,
+    };
+    let slug = multipart
+        .get("slug")
+        .and_then(|v| v.as_str())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty());
     let (filename, bytes) = match multipart.get("upload_file") {
         Some(MultipartData::File(filename, bytes)) => (filename, bytes),
         _ => return Ok(Response::builder(StatusCode::BadRequest).body("Invalid multipart request").build()),
@@ -126,7 +273,19 @@ pub async fn upload(mut request: Request<Arc<State>>) -> TideResult {
 
     let state = request.state().clone();
 
-    let validated_image = match validate_image_file(filename, bytes) {
+    if let Some(slug) = &slug {
+        if let Err(e) = check_slug(&state.pool, slug, None).await {
+            let session = request.session_mut();
+            let flashes = vec![Flash::Error(format!("Invalid slug: {}", e))];
+            swap_flashes(session, flashes)?;
+            return Ok(Redirect::new("/").into());
+        }
+    }
+
+    let decode_started = Instant::now();
+    let validated_image = validate_image_file(filename, bytes);
+    state.metrics.observe_decode(decode_started.elapsed());
+    let validated_image = match validated_image {
         Ok(image) => image,
         Err(e) => {
             let session = request.session_mut();
@@ -135,17 +294,39 @@ pub async fn upload(mut request: Request<Arc<State>>) -> TideResult {
             return Ok(Redirect::new("/").into());
         }
     };
-    let thumbnail = create_thumbnail(&validated_image.image);
 
-    let record = reserve_media_record(&state.pool, &validated_image, thumbnail.is_some(), private).await?;
-    let filename = state.media_root.join(format!("{}.{}", record.hash_id, record.extension));
-    let thumb_filename = state.media_root.join(format!("thumbnails/{}.jpg", record.hash_id));
-    if let Some(thumb) = thumbnail {
-        spawn(async move { save_image(&thumb, ImageFormat::Jpeg, &thumb_filename) }).await?;
-        spawn(async move { save_image(&validated_image.image, validated_image.format, &filename) }).await?;
-    } else {
-        spawn(async move { save_image(&validated_image.image, validated_image.format, &filename) }).await?;
-    }
+    let options = UploadOptions {
+        private,
+        strip_exif,
+        expires_at,
+        max_views,
+        slug,
+        ..Default::default()
+    };
+    let stored = match store_media(&state, validated_image, &options).await {
+        Err(e) if e.is::<SlugTakenError>() => {
+            let session = request.session_mut();
+            let flashes = vec![Flash::Error(format!("Invalid slug: {}", e))];
+            swap_flashes(session, flashes)?;
+            return Ok(Redirect::new("/").into());
+        }
+        stored => stored?,
+    };
+    let record = match stored {
+        StoredMedia::Created(record) => {
+            notify(&state, WebhookEvent::Uploaded, &record).await;
+            record
+        }
+        StoredMedia::Existing(existing) => {
+            let session = request.session_mut();
+            let flashes = vec![Flash::Info(format!(
+                "This media has already been uploaded. ID is {}",
+                existing.hash_id
+            ))];
+            swap_flashes(session, flashes)?;
+            return Ok(Redirect::new(format!("/m/{}", existing.hash_id)).into());
+        }
+    };
 
     let session = request.session_mut();
     let flashes = vec![Flash::Info(format!(
@@ -156,6 +337,19 @@ pub async fn upload(mut request: Request<Arc<State>>) -> TideResult {
     Ok(Redirect::new(format!("/m/{}", record.hash_id)).into())
 }
 
+/// Parses the value of `<input type="datetime-local">` as local time.
+fn parse_local_datetime(value: &str) -> Result<OffsetDateTime> {
+    let datetime = match PrimitiveDateTime::parse(value, format_description!("[year]-[month]-[day]T[hour]:[minute]")) {
+        Ok(dt) => dt,
+        Err(_) => PrimitiveDateTime::parse(value, format_description!("[year]-[month]-[day]T[hour]:[minute]:[second]"))?,
+    };
+    let datetime = datetime.assume_offset(UtcOffset::current_local_offset()?);
+    if datetime <= OffsetDateTime::now_local()? {
+        bail!("The date is in the past");
+    }
+    Ok(datetime)
+}
+
 /// PATCH `/m/:hash_id`
 /// Updates media information.
 pub async fn update(mut request: Request<Arc<State>>) -> TideResult {
@@ -163,6 +357,7 @@ pub async fn update(mut request: Request<Arc<State>>) -> TideResult {
     struct Parameters {
         comment: String,
         private: Option<bool>,
+        slug: Option<String>,
     }
 
     debug!("Performing PATCH /m/:hash_id");
@@ -181,6 +376,24 @@ pub async fn update(mut request: Request<Arc<State>>) -> TideResult {
             return Ok(Redirect::new("/").into());
         }
     };
+    let new_slug = params.slug.as_deref().map(|s| s.trim()).filter(|s| !s.is_empty());
+    if new_slug != media_record.slug.as_deref() {
+        if let Some(slug) = new_slug {
+            if let Err(e) = check_slug(&state.pool, slug, Some(&media_record.hash_id)).await {
+                let flashes = vec![Flash::Error(format!("Invalid slug: {}", e))];
+                swap_flashes(session, flashes)?;
+                return Ok(Redirect::new(format!("/m/{}", media_record.hash_id)).into());
+            }
+        }
+        match update_media_slug(&state.pool, &media_record, new_slug).await {
+            Err(e) if e.is::<SlugTakenError>() => {
+                let flashes = vec![Flash::Error(format!("Invalid slug: {}", e))];
+                swap_flashes(session, flashes)?;
+                return Ok(Redirect::new(format!("/m/{}", media_record.hash_id)).into());
+            }
+            updated => updated?,
+        };
+    }
     let new_record = update_media_record(
         &state.pool,
         &media_record.hash_id,
@@ -188,14 +401,15 @@ pub async fn update(mut request: Request<Arc<State>>) -> TideResult {
         &params.comment,
     )
     .await?;
+    notify(&state, WebhookEvent::Updated, &new_record).await;
 
     let flashes = vec![Flash::Info(format!("Media information has been updated successfully."))];
     swap_flashes(session, flashes)?;
-    Ok(Redirect::new(format!("/m/{}", new_record.hash_id)).into())
+    Ok(Redirect::new(format!("/m/{}", new_record.public_id())).into())
 }
 
 /// DELETE `/m/:hash_id`
-/// Deletes a file.
+/// Moves a file into the trash.
 pub async fn delete(mut request: Request<Arc<State>>) -> TideResult {
     debug!("Performing DELETE /m/:hash_id");
     ensure_login!(request);
@@ -212,18 +426,135 @@ pub async fn delete(mut request: Request<Arc<State>>) -> TideResult {
             return Ok(Redirect::new("/").into());
         }
     };
+    trash_media_record(&state.pool, &media_record.hash_id).await?;
+    notify(&state, WebhookEvent::Deleted, &media_record).await;
 
-    let filename = state
-        .media_root
-        .join(format!("{}.{}", media_record.hash_id, media_record.extension));
-    fs::remove_file(&filename).await?;
-    if media_record.has_thumbnail {
-        let thumb_filename = state.media_root.join(format!("thumbnails/{}.jpg", media_record.hash_id));
-        fs::remove_file(&thumb_filename).await?;
-    }
-    remove_media_record(&state.pool, &media_record.hash_id).await?;
-
-    let flashes = vec![Flash::Info(format!("Media has been deleted successfully."))];
+    let flashes = vec![Flash::Info(format!("Media has been moved to the trash."))];
     swap_flashes(session, flashes)?;
     Ok(Redirect::new("/").into())
 }
+
+/// `GET /media/*path`
+/// Serves an original media or its thumbnail unless it is in the trash or has expired.
+/// Serving an original media counts up its views.
+pub async fn serve(request: Request<Arc<State>>) -> TideResult {
+    debug!("Serving /media/*path");
+
+    let state = request.state().clone();
+    let path = request.param("path").expect("path must be set");
+    let (is_thumbnail, filename) = match path.strip_prefix("thumbnails/") {
+        Some(filename) => (true, filename),
+        None => (false, path),
+    };
+    let (hash_id, extension) = match filename.rsplit_once('.') {
+        Some(pair) => pair,
+        None => return Ok(Response::builder(StatusCode::NotFound).body("Media not found").build()),
+    };
+
+    let media_record = match fetch_media_by_key(&state.pool, hash_id).await? {
+        Some(m) => m,
+        None => match fetch_media_by_slug_alias(&state.pool, hash_id).await? {
+            Some(moved) => {
+                let prefix = if is_thumbnail { "thumbnails/" } else { "" };
+                let location = format!("/media/{}{}.{}", prefix, moved.public_id(), extension);
+                return Ok(Redirect::permanent(location).into());
+            }
+            None if is_media_tombstoned(&state.pool, hash_id).await? => {
+                return Ok(Response::builder(StatusCode::Gone).body("Media expired").build());
+            }
+            None => return Ok(Response::builder(StatusCode::NotFound).body("Media not found").build()),
+        },
+    };
+    if media_record.is_expired(OffsetDateTime::now_local()?) {
+        return Ok(Response::builder(StatusCode::Gone).body("Media expired").build());
+    }
+
+    let (local_path, content_type, tag) = if !is_thumbnail && extension == media_record.extension {
+        match negotiate_variant(&request, &media_record) {
+            Some((variant, variant_mime)) => (
+                state.media_root.join(media_record.variant_filename(variant)),
+                variant_mime.parse().ok(),
+                format!("{}-{}", media_record.storage_id, variant),
+            ),
+            None => (
+                state.media_root.join(media_record.original_filename()),
+                Mime::from_extension(&media_record.extension),
+                media_record.storage_id.clone(),
+            ),
+        }
+    } else if is_thumbnail && media_record.has_thumbnail && extension == "jpg" {
+        (
+            state.media_root.join(media_record.thumbnail_filename()),
+            Some(mime::JPEG),
+            media_record.storage_id.clone(),
+        )
+    } else {
+        return Ok(Response::builder(StatusCode::NotFound).body("Media not found").build());
+    };
+
+    let file = StaticFile::open(local_path, &tag).await?;
+    let delivery = evaluate_delivery(&request, &file, &media_record, is_thumbnail);
+    if !is_thumbnail && delivery.sends_beginning() && !consume_media_view(&state.pool, &media_record.hash_id).await? {
+        return Ok(Response::builder(StatusCode::Gone).body("Media expired").build());
+    }
+
+    let cache_control = cache_control(&media_record, hash_id, is_thumbnail);
+    let mut response = file.respond(delivery, content_type, cache_control).await?;
+    if !is_thumbnail && !media_record.available_variants().is_empty() {
+        response.insert_header("Vary", "Accept");
+    }
+    Ok(response)
+}
+
+/// Chooses the most preferred derivative which the client accepts.
+/// Only exact media types count; wildcards like `image/*` don't select derivatives.
+fn negotiate_variant(request: &Request<Arc<State>>, media: &Media) -> Option<(&'static str, &'static str)> {
+    let accept: Vec<_> = request.header("Accept")?.iter().map(|v| v.as_str().to_string()).collect();
+    let accepted: Vec<_> = accept
+        .iter()
+        .flat_map(|v| v.split(','))
+        .filter_map(|item| {
+            let mut params = item.split(';').map(|p| p.trim());
+            let essence = params.next()?;
+            let refused = params.any(|p| match p.strip_prefix("q=") {
+                Some(q) => q.parse::<f32>().map(|q| q <= 0.0).unwrap_or(false),
+                None => false,
+            });
+            if refused {
+                None
+            } else {
+                Some(essence)
+            }
+        })
+        .collect();
+
+    VARIANT_FORMATS
+        .iter()
+        .find(|(extension, variant_mime)| media.available_variants().iter().any(|v| v == extension) && accepted.contains(variant_mime))
+        .copied()
+}
+
+/// Decides delivery of a stored file.
+/// Originals of view-limited media are always sent as a whole, since partial or conditional responses would
+/// let clients fetch the file without consuming views.
+fn evaluate_delivery(request: &Request<Arc<State>>, file: &StaticFile, media: &Media, is_thumbnail: bool) -> Delivery {
+    if media.max_views.is_some() && !is_thumbnail {
+        Delivery::Full
+    } else {
+        file.evaluate(request)
+    }
+}
+
+/// Determines `Cache-Control` for stored files.
+/// Content at a hash ID never changes, so originals and variants requested by hash ID are cached as immutable.
+/// Slugs may be moved to other media and thumbnails may be regenerated, so they are revalidated after a short while.
+/// Media which may expire are never cached so that every view is counted.
+fn cache_control(media: &Media, requested_key: &str, is_thumbnail: bool) -> &'static str {
+    if media.expires_at.is_some() || media.max_views.is_some() {
+        "no-store"
+    } else if !is_thumbnail && requested_key == media.hash_id {
+        "public, max-age=31536000, immutable"
+    } else {
+        "public, max-age=300, must-revalidate"
+    }
+}
@@ -0,0 +1,33 @@
+//! Contains metrics endpoint.
+
+use crate::application::State;
+
+use async_std::sync::Arc;
+
+use tide::{http::StatusCode, Request, Response, Result as TideResult};
+
+/// `GET /metrics`
+/// Exposes metrics in Prometheus text format.
+/// If `METRICS_TOKEN` is set, it must be given as a Bearer token.
+pub async fn metrics(request: Request<Arc<State>>) -> TideResult {
+    let state = request.state();
+    if let Some(token) = &state.metrics_token {
+        let authorized = request
+            .header("Authorization")
+            .and_then(|h| h.as_str().strip_prefix("Bearer "))
+            .map(|t| t.trim() == token)
+            .unwrap_or(false);
+        if !authorized {
+            state.metrics.record_auth_failure("metrics");
+            return Ok(Response::builder(StatusCode::Unauthorized)
+                .header("WWW-Authenticate", "Bearer")
+                .body("Authorization needed")
+                .build());
+        }
+    }
+
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(state.metrics.render(&state.pool))
+        .build())
+}
@@ -1,7 +1,13 @@
 //! Contains root-level endpoints.
 
 pub(crate) mod auth;
+pub(crate) mod feed;
+pub(crate) mod health;
 pub(crate) mod media;
+pub(crate) mod metrics;
+pub(crate) mod oembed;
+pub(crate) mod trash;
+pub(crate) mod webhook;
 
 use crate::{
     action::{database::fetch_records_count, session::Common},
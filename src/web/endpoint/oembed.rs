@@ -0,0 +1,211 @@
+//! Contains oEmbed provider endpoint.
+
+use crate::{
+    action::{database::fetch_media_by_key, media::thumbnail_dimensions},
+    application::State,
+    entity::Media,
+};
+
+use async_std::sync::Arc;
+
+use anyhow::Result;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use tide::{http::StatusCode, Request, Response, Result as TideResult};
+use time::OffsetDateTime;
+use url::Url;
+
+const PROVIDER_NAME: &str = "Kebisafe";
+
+/// oEmbed `photo` response.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PhotoResponse {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub version: &'static str,
+    pub title: String,
+    pub provider_name: &'static str,
+    pub provider_url: String,
+    pub url: String,
+    pub width: u32,
+    pub height: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail_height: Option<u32>,
+}
+
+impl PhotoResponse {
+    /// Constructs from `Media`.
+    /// `url` refers to the original if it fits in `max_size`, otherwise to the thumbnail if that fits.
+    /// Returns `None` when neither fits, since images are not served at any other size.
+    fn from_media_record(state: &State, media: &Media, max_size: (Option<u32>, Option<u32>)) -> Result<Option<PhotoResponse>> {
+        let public_id = media.public_id();
+        let original_url = state.hosted_at.join(&format!("/media/{}.{}", public_id, media.extension))?;
+        let original = (original_url.to_string(), media.width as u32, media.height as u32);
+
+        let thumbnail = if media.has_thumbnail {
+            let (w, h) = thumbnail_dimensions(media.width as u32, media.height as u32, state.thumbnail_mode, state.thumbnail_size);
+            let url = state.hosted_at.join(&format!("/media/thumbnails/{}.jpg", public_id))?;
+            Some((url.to_string(), w, h))
+        } else {
+            None
+        };
+
+        let (url, width, height) = if fits_in(original.1, original.2, max_size) {
+            original
+        } else {
+            match &thumbnail {
+                Some((url, w, h)) if fits_in(*w, *h, max_size) => (url.clone(), *w, *h),
+                _ => return Ok(None),
+            }
+        };
+        let (thumbnail_url, thumbnail_width, thumbnail_height) = match thumbnail {
+            Some((url, w, h)) => (Some(url), Some(w), Some(h)),
+            None => (None, None, None),
+        };
+
+        Ok(Some(PhotoResponse {
+            kind: "photo",
+            version: "1.0",
+            title: media.comment.clone().unwrap_or_else(|| format!("Media #{}", media.hash_id)),
+            provider_name: PROVIDER_NAME,
+            provider_url: state.hosted_at.to_string(),
+            url,
+            width,
+            height,
+            thumbnail_url,
+            thumbnail_width,
+            thumbnail_height,
+        }))
+    }
+
+    /// Serializes into oEmbed XML format.
+    fn to_xml(&self) -> String {
+        let mut fields = vec![
+            ("type", self.kind.to_string()),
+            ("version", self.version.to_string()),
+            ("title", self.title.clone()),
+            ("provider_name", self.provider_name.to_string()),
+            ("provider_url", self.provider_url.clone()),
+            ("url", self.url.clone()),
+            ("width", self.width.to_string()),
+            ("height", self.height.to_string()),
+        ];
+        if let (Some(url), Some(width), Some(height)) = (&self.thumbnail_url, self.thumbnail_width, self.thumbnail_height) {
+            fields.push(("thumbnail_url", url.clone()));
+            fields.push(("thumbnail_width", width.to_string()));
+            fields.push(("thumbnail_height", height.to_string()));
+        }
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\" standalone=\"yes\"?>\n<oembed>\n");
+        for (name, value) in fields {
+            xml.push_str(&format!("  <{0}>{1}</{0}>\n", name, escape_xml(&value)));
+        }
+        xml.push_str("</oembed>\n");
+        xml
+    }
+}
+
+/// `GET /oembed`
+/// Returns oEmbed response for a media page or permalink.
+pub async fn oembed(request: Request<Arc<State>>) -> TideResult {
+    #[derive(Deserialize)]
+    struct Parameters {
+        url: String,
+        format: Option<String>,
+        maxwidth: Option<u32>,
+        maxheight: Option<u32>,
+    }
+
+    debug!("Responding /oembed");
+
+    let state = request.state().clone();
+    let query: Parameters = match request.query() {
+        Ok(q) => q,
+        Err(_) => return Ok(Response::builder(StatusCode::BadRequest).body("Invalid parameters").build()),
+    };
+    let is_xml = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("xml") => true,
+        Some(_) => return Ok(Response::builder(StatusCode::NotImplemented).body("Unsupported format").build()),
+    };
+
+    let key = match media_key(&state.hosted_at, &query.url) {
+        Some(key) => key,
+        None => return Ok(Response::builder(StatusCode::NotFound).body("Media not found").build()),
+    };
+    let media_record = match fetch_media_by_key(&state.pool, &key).await? {
+        Some(m) if !m.is_expired(OffsetDateTime::now_local()?) => m,
+        _ => return Ok(Response::builder(StatusCode::NotFound).body("Media not found").build()),
+    };
+    if media_record.is_private {
+        return Ok(Response::builder(StatusCode::Unauthorized).body("Media is private").build());
+    }
+    // Embedding the original would consume views on every load
+    if media_record.max_views.is_some() {
+        return Ok(Response::builder(StatusCode::NotFound).body("Media has limited views").build());
+    }
+
+    let photo = match PhotoResponse::from_media_record(&state, &media_record, (query.maxwidth, query.maxheight))? {
+        Some(photo) => photo,
+        None => return Ok(Response::builder(StatusCode::NotFound).body("No image fits in the size").build()),
+    };
+    let response = if is_xml {
+        Response::builder(StatusCode::Ok)
+            .content_type("text/xml; charset=utf-8")
+            .body(photo.to_xml())
+            .build()
+    } else {
+        Response::builder(StatusCode::Ok)
+            .content_type("application/json; charset=utf-8")
+            .body(serde_json::to_string(&photo)?)
+            .build()
+    };
+    Ok(response)
+}
+
+/// Builds discovery URL of the oEmbed endpoint for the page.
+pub fn discovery_url(hosted_at: &Url, page_url: &Url, format: &str) -> Result<Url> {
+    let mut url = hosted_at.join("/oembed")?;
+    url.query_pairs_mut()
+        .append_pair("url", page_url.as_str())
+        .append_pair("format", format);
+    Ok(url)
+}
+
+/// Extracts hash ID or slug from `/m/{key}` or `/media/{key}.{ext}` on this instance.
+fn media_key(hosted_at: &Url, target: &str) -> Option<String> {
+    let target = Url::parse(target).ok()?;
+    if target.host_str() != hosted_at.host_str() || target.port_or_known_default() != hosted_at.port_or_known_default() {
+        return None;
+    }
+
+    let segments: Vec<_> = target.path_segments()?.collect();
+    let key = match segments.as_slice() {
+        ["m", key] => *key,
+        ["media", filename] => filename.split_once('.')?.0,
+        _ => return None,
+    };
+    if key.is_empty() {
+        None
+    } else {
+        Some(key.to_string())
+    }
+}
+
+/// Checks whether the size fits in the maximum size.
+fn fits_in(width: u32, height: u32, (max_width, max_height): (Option<u32>, Option<u32>)) -> bool {
+    max_width.map(|m| width <= m).unwrap_or(true) && max_height.map(|m| height <= m).unwrap_or(true)
+}
+
+/// Escapes text content of XML.
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
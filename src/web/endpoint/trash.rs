@@ -0,0 +1,99 @@
+//! Contains trash endpoints.
+
+use crate::{
+    action::{
+        database::{fetch_trashed_media, fetch_trashed_media_list, restore_media_record},
+        media::purge_media,
+        session::{swap_flashes, Common, Flash},
+        webhook::{notify, WebhookEvent},
+    },
+    application::State,
+    ensure_login,
+    web::template,
+};
+
+use async_std::sync::Arc;
+
+use log::debug;
+use tide::{
+    http::{mime, StatusCode},
+    Redirect, Request, Response, Result as TideResult,
+};
+use yarte::Template;
+
+/// `GET /trash/`
+/// Shows media in the trash.
+pub async fn list_trash(mut request: Request<Arc<State>>) -> TideResult {
+    debug!("Rendering /trash/");
+    ensure_login!(request);
+
+    let state = request.state().clone();
+    let session = request.session_mut();
+
+    let info = template::PageInfo::new(&state, "/trash/")?.with_title("Trash");
+    let common = Common::new(&state, session, vec![])?;
+    let media_list = fetch_trashed_media_list(&state.pool).await?;
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(mime::HTML)
+        .body(
+            template::TrashIndex {
+                info,
+                common,
+                media_list,
+                retention_days: state.trash_retention.whole_days(),
+            }
+            .call()?,
+        )
+        .build())
+}
+
+/// PATCH `/trash/:hash_id`
+/// Restores a media from the trash.
+pub async fn restore(mut request: Request<Arc<State>>) -> TideResult {
+    debug!("Performing PATCH /trash/:hash_id");
+    ensure_login!(request);
+
+    let state = request.state().clone();
+    let hash_id = request.param("hash_id").expect("hash_id must be set").to_string();
+    let session = request.session_mut();
+
+    let media_record = match fetch_trashed_media(&state.pool, &hash_id).await? {
+        Some(m) => m,
+        None => {
+            let flashes = vec![Flash::Error(format!("Media {} not found in the trash", hash_id))];
+            swap_flashes(session, flashes)?;
+            return Ok(Redirect::new("/trash/").into());
+        }
+    };
+    restore_media_record(&state.pool, &media_record.hash_id).await?;
+
+    let flashes = vec![Flash::Info(format!("Media has been restored successfully."))];
+    swap_flashes(session, flashes)?;
+    Ok(Redirect::new(format!("/m/{}", media_record.hash_id)).into())
+}
+
+/// DELETE `/trash/:hash_id`
+/// Deletes a file permanently.
+pub async fn purge(mut request: Request<Arc<State>>) -> TideResult {
+    debug!("Performing DELETE /trash/:hash_id");
+    ensure_login!(request);
+
+    let state = request.state().clone();
+    let hash_id = request.param("hash_id").expect("hash_id must be set").to_string();
+    let session = request.session_mut();
+
+    let media_record = match fetch_trashed_media(&state.pool, &hash_id).await? {
+        Some(m) => m,
+        None => {
+            let flashes = vec![Flash::Error(format!("Media {} not found in the trash", hash_id))];
+            swap_flashes(session, flashes)?;
+            return Ok(Redirect::new("/trash/").into());
+        }
+    };
+    purge_media(&state.pool, &state.media_root, &media_record).await?;
+    notify(&state, WebhookEvent::Deleted, &media_record).await;
+
+    let flashes = vec![Flash::Info(format!("Media has been deleted permanently."))];
+    swap_flashes(session, flashes)?;
+    Ok(Redirect::new("/trash/").into())
+}
@@ -0,0 +1,174 @@
+//! Contains webhook endpoints.
+
+use crate::{
+    action::{
+        database::{fetch_recent_webhook_deliveries, fetch_webhook, fetch_webhooks, insert_webhook, remove_webhook, update_webhook_enabled},
+        session::{swap_flashes, Common, Flash},
+        webhook::{generate_secret, WebhookEvent},
+    },
+    application::State,
+    ensure_login, validate_form,
+    web::template,
+};
+
+use async_std::sync::Arc;
+
+use log::debug;
+use serde::Deserialize;
+use tide::{
+    http::{mime, StatusCode},
+    Redirect, Request, Response, Result as TideResult,
+};
+use url::Url;
+use yarte::Template;
+
+const DELIVERY_HISTORY_COUNT: usize = 50;
+
+/// `GET /webhooks/`
+/// Shows webhooks and recent deliveries.
+pub async fn list_webhooks(mut request: Request<Arc<State>>) -> TideResult {
+    debug!("Rendering /webhooks/");
+    ensure_login!(request);
+
+    let state = request.state().clone();
+    let session = request.session_mut();
+
+    let info = template::PageInfo::new(&state, "/webhooks/")?.with_title("Webhooks");
+    let common = Common::new(&state, session, vec![])?;
+    let webhooks = fetch_webhooks(&state.pool).await?;
+    let deliveries = fetch_recent_webhook_deliveries(&state.pool, DELIVERY_HISTORY_COUNT).await?;
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(mime::HTML)
+        .body(
+            template::WebhookIndex {
+                info,
+                common,
+                webhooks,
+                deliveries,
+                events: WebhookEvent::ALL.iter().map(|e| e.as_str()).collect(),
+            }
+            .call()?,
+        )
+        .build())
+}
+
+/// POST `/webhooks/`
+/// Registers a webhook.
+pub async fn create(mut request: Request<Arc<State>>) -> TideResult {
+    #[derive(Deserialize)]
+    struct Parameters {
+        url: String,
+        #[serde(rename = "media.uploaded")]
+        uploaded: Option<String>,
+        #[serde(rename = "media.updated")]
+        updated: Option<String>,
+        #[serde(rename = "media.deleted")]
+        deleted: Option<String>,
+    }
+
+    debug!("Performing POST /webhooks/");
+    ensure_login!(request);
+
+    let state = request.state().clone();
+    let params = validate_form!(Parameters, request, "/webhooks/");
+    let session = request.session_mut();
+
+    let url = params.url.trim();
+    match Url::parse(url) {
+        Ok(u) if u.scheme() == "http" || u.scheme() == "https" => (),
+        _ => {
+            let flashes = vec![Flash::Error(format!("Invalid webhook URL: {}", url))];
+            swap_flashes(session, flashes)?;
+            return Ok(Redirect::new("/webhooks/").into());
+        }
+    }
+    let events: Vec<_> = [
+        (WebhookEvent::Uploaded, &params.uploaded),
+        (WebhookEvent::Updated, &params.updated),
+        (WebhookEvent::Deleted, &params.deleted),
+    ]
+    .into_iter()
+    .filter(|(_, checked)| checked.is_some())
+    .map(|(event, _)| event.as_str().to_string())
+    .collect();
+    if events.is_empty() {
+        let flashes = vec![Flash::Error(format!("Select at least one event"))];
+        swap_flashes(session, flashes)?;
+        return Ok(Redirect::new("/webhooks/").into());
+    }
+
+    let webhook = insert_webhook(&state.pool, url, &generate_secret(), &events).await?;
+
+    let flashes = vec![Flash::Info(format!("Webhook #{} has been registered.", webhook.id))];
+    swap_flashes(session, flashes)?;
+    Ok(Redirect::new("/webhooks/").into())
+}
+
+/// PATCH `/webhooks/:id`
+/// Enables or disables a webhook.
+pub async fn update(mut request: Request<Arc<State>>) -> TideResult {
+    #[derive(Deserialize)]
+    struct Parameters {
+        enabled: Option<String>,
+    }
+
+    debug!("Performing PATCH /webhooks/:id");
+    ensure_login!(request);
+
+    let state = request.state().clone();
+    let id = request.param("id").expect("id must be set").to_string();
+    let params = validate_form!(Parameters, request, "/webhooks/");
+    let session = request.session_mut();
+
+    let webhook = match id.parse().ok() {
+        Some(id) => fetch_webhook(&state.pool, id).await?,
+        None => None,
+    };
+    let webhook = match webhook {
+        Some(w) => w,
+        None => {
+            let flashes = vec![Flash::Error(format!("Webhook #{} not found", id))];
+            swap_flashes(session, flashes)?;
+            return Ok(Redirect::new("/webhooks/").into());
+        }
+    };
+    let enabled = params.enabled.as_deref() == Some("true");
+    update_webhook_enabled(&state.pool, webhook.id, enabled).await?;
+
+    let flashes = vec![Flash::Info(format!(
+        "Webhook #{} has been {}.",
+        webhook.id,
+        if enabled { "enabled" } else { "disabled" }
+    ))];
+    swap_flashes(session, flashes)?;
+    Ok(Redirect::new("/webhooks/").into())
+}
+
+/// DELETE `/webhooks/:id`
+/// Deletes a webhook with its delivery history.
+pub async fn delete(mut request: Request<Arc<State>>) -> TideResult {
+    debug!("Performing DELETE /webhooks/:id");
+    ensure_login!(request);
+
+    let state = request.state().clone();
+    let id = request.param("id").expect("id must be set").to_string();
+    let session = request.session_mut();
+
+    let webhook = match id.parse().ok() {
+        Some(id) => fetch_webhook(&state.pool, id).await?,
+        None => None,
+    };
+    let webhook = match webhook {
+        Some(w) => w,
+        None => {
+            let flashes = vec![Flash::Error(format!("Webhook #{} not found", id))];
+            swap_flashes(session, flashes)?;
+            return Ok(Redirect::new("/webhooks/").into());
+        }
+    };
+    remove_webhook(&state.pool, webhook.id).await?;
+
+    let flashes = vec![Flash::Info(format!("Webhook #{} has been deleted.", webhook.id))];
+    swap_flashes(session, flashes)?;
+    Ok(Redirect::new("/webhooks/").into())
+}
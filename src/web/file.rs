@@ -0,0 +1,293 @@
+//! Contains file responses with conditional and range requests.
+
+use async_std::{
+    fs::File,
+    io::{prelude::*, BufReader, SeekFrom},
+    path::{Path, PathBuf},
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use tide::{
+    http::{
+        utils::{fmt_http_date, parse_http_date},
+        Mime, StatusCode,
+    },
+    Body, Request, Response,
+};
+
+/// How a file should be delivered for a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// `304 Not Modified`
+    NotModified,
+
+    /// `200 OK` with the whole file
+    Full,
+
+    /// `206 Partial Content` with inclusive byte range
+    Partial(u64, u64),
+
+    /// `416 Range Not Satisfiable`
+    Unsatisfiable,
+}
+
+impl Delivery {
+    /// Whether the response body includes the beginning of the file.
+    pub fn sends_beginning(&self) -> bool {
+        matches!(self, Delivery::Full | Delivery::Partial(0, _))
+    }
+}
+
+/// A file on disk to be served.
+#[derive(Debug, Clone)]
+pub struct StaticFile {
+    path: PathBuf,
+    length: u64,
+    modified: SystemTime,
+    etag: String,
+}
+
+impl StaticFile {
+    /// Reads metadata of the file.
+    /// ETag is derived from `tag`, file length and modification time, so rewriting the file changes it.
+    pub async fn open(path: impl AsRef<Path>, tag: &str) -> Result<StaticFile> {
+        let path = path.as_ref().to_path_buf();
+        let metadata = path.metadata().await?;
+        let length = metadata.len();
+        let modified = metadata.modified()?;
+        let modified_secs = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+        Ok(StaticFile {
+            path,
+            length,
+            modified,
+            etag: format!("\"{}-{:x}-{:x}\"", tag, length, modified_secs),
+        })
+    }
+
+    /// Decides delivery from `If-None-Match`, `If-Modified-Since`, `Range` and `If-Range`.
+    pub fn evaluate<S>(&self, request: &Request<S>) -> Delivery {
+        self.evaluate_headers(|name| request.header(name).map(|v| v.last().as_str().to_string()))
+    }
+
+    /// Decides delivery from header values looked up by name.
+    fn evaluate_headers(&self, header: impl Fn(&str) -> Option<String>) -> Delivery {
+        // If-None-Match takes precedence over If-Modified-Since
+        if let Some(if_none_match) = header("If-None-Match") {
+            if etag_matches(&if_none_match, &self.etag) {
+                return Delivery::NotModified;
+            }
+        } else if let Some(if_modified_since) = header("If-Modified-Since") {
+            if let Ok(since) = parse_http_date(&if_modified_since) {
+                if truncate_to_secs(self.modified) <= since {
+                    return Delivery::NotModified;
+                }
+            }
+        }
+
+        let range = match header("Range") {
+            Some(range) => range,
+            None => return Delivery::Full,
+        };
+        if let Some(if_range) = header("If-Range") {
+            let fresh = match parse_http_date(&if_range) {
+                Ok(date) => truncate_to_secs(self.modified) == date,
+                Err(_) => if_range.trim() == self.etag,
+            };
+            if !fresh {
+                return Delivery::Full;
+            }
+        }
+        parse_range(&range, self.length)
+    }
+
+    /// Builds the response for the delivery.
+    pub async fn respond(self, delivery: Delivery, content_type: Option<Mime>, cache_control: &str) -> Result<Response> {
+        let (status, content_range, range) = match delivery {
+            Delivery::NotModified => (StatusCode::NotModified, None, None),
+            Delivery::Unsatisfiable => (
+                StatusCode::RequestedRangeNotSatisfiable,
+                Some(format!("bytes */{}", self.length)),
+                None,
+            ),
+            Delivery::Full => (StatusCode::Ok, None, Some((0, self.length))),
+            Delivery::Partial(start, end) => (
+                StatusCode::PartialContent,
+                Some(format!("bytes {}-{}/{}", start, end, self.length)),
+                Some((start, end - start + 1)),
+            ),
+        };
+
+        let mut builder = Response::builder(status)
+            .header("ETag", &self.etag)
+            .header("Last-Modified", fmt_http_date(self.modified))
+            .header("Cache-Control", cache_control)
+            .header("Accept-Ranges", "bytes");
+        if let Some(content_range) = content_range {
+            builder = builder.header("Content-Range", content_range);
+        }
+        let (start, length) = match range {
+            Some(range) => range,
+            None => return Ok(builder.build()),
+        };
+
+        let mut file = File::open(&self.path).await?;
+        if start > 0 {
+            file.seek(SeekFrom::Start(start)).await?;
+        }
+        let mut body = Body::from_reader(BufReader::new(file.take(length)), Some(length as usize));
+        if let Some(mime) = content_type {
+            body.set_mime(mime);
+        }
+        Ok(builder.body(body).build())
+    }
+}
+
+/// Checks `If-None-Match` value with weak comparison.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(|t| t.trim())
+        .any(|t| t == "*" || t.trim_start_matches("W/") == etag)
+}
+
+/// HTTP dates have only seconds.
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    UNIX_EPOCH + std::time::Duration::from_secs(secs)
+}
+
+/// Parses a single byte range.
+/// Unsupported forms such as multiple ranges fall back to the whole file.
+fn parse_range(range: &str, length: u64) -> Delivery {
+    let spec = match range.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Delivery::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(pair) => pair,
+        None => return Delivery::Full,
+    };
+
+    let (start, end) = match (start.parse::<u64>().ok(), end.parse::<u64>().ok()) {
+        // bytes=-500
+        (None, Some(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return Delivery::Unsatisfiable;
+            }
+            (length.saturating_sub(suffix), length.saturating_sub(1))
+        }
+        // bytes=500-
+        (Some(start), None) if end.is_empty() => (start, length.saturating_sub(1)),
+        // bytes=500-999
+        (Some(start), Some(end)) if start <= end => (start, end.min(length.saturating_sub(1))),
+        _ => return Delivery::Full,
+    };
+
+    if length == 0 || start >= length {
+        Delivery::Unsatisfiable
+    } else {
+        Delivery::Partial(start, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    const LENGTH: u64 = 1000;
+    const ETAG: &str = "\"tag-3e8-5f5e1000\"";
+
+    fn modified() -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(1_600_000_000_500)
+    }
+
+    fn file() -> StaticFile {
+        StaticFile {
+            path: PathBuf::from("unused"),
+            length: LENGTH,
+            modified: modified(),
+            etag: ETAG.to_string(),
+        }
+    }
+
+    fn evaluate(headers: &[(&str, &str)]) -> Delivery {
+        file().evaluate_headers(|name| headers.iter().find(|(n, _)| *n == name).map(|(_, v)| v.to_string()))
+    }
+
+    #[test]
+    fn etag_matches_weakly() {
+        assert!(etag_matches(ETAG, ETAG));
+        assert!(etag_matches(&format!("W/{}", ETAG), ETAG));
+        assert!(etag_matches(&format!("\"other\", {}", ETAG), ETAG));
+        assert!(etag_matches("*", ETAG));
+        assert!(!etag_matches("\"other\"", ETAG));
+        assert!(!etag_matches("", ETAG));
+    }
+
+    #[test]
+    fn parse_range_accepts_single_ranges() {
+        assert_eq!(parse_range("bytes=0-499", LENGTH), Delivery::Partial(0, 499));
+        assert_eq!(parse_range("bytes=500-", LENGTH), Delivery::Partial(500, 999));
+        assert_eq!(parse_range("bytes=-200", LENGTH), Delivery::Partial(800, 999));
+        assert_eq!(parse_range("bytes=900-5000", LENGTH), Delivery::Partial(900, 999));
+        assert_eq!(parse_range("bytes=-5000", LENGTH), Delivery::Partial(0, 999));
+        assert_eq!(parse_range(" bytes=10-19 ", LENGTH), Delivery::Partial(10, 19));
+    }
+
+    #[test]
+    fn parse_range_rejects_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=1000-", LENGTH), Delivery::Unsatisfiable);
+        assert_eq!(parse_range("bytes=1000-1999", LENGTH), Delivery::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", LENGTH), Delivery::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), Delivery::Unsatisfiable);
+    }
+
+    #[test]
+    fn parse_range_falls_back_to_full() {
+        assert_eq!(parse_range("bytes=0-1,5-9", LENGTH), Delivery::Full);
+        assert_eq!(parse_range("bytes=500-100", LENGTH), Delivery::Full);
+        assert_eq!(parse_range("bytes=abc", LENGTH), Delivery::Full);
+        assert_eq!(parse_range("items=0-1", LENGTH), Delivery::Full);
+    }
+
+    #[test]
+    fn evaluate_conditional_requests() {
+        let date = fmt_http_date(modified());
+        let earlier = fmt_http_date(modified() - Duration::from_secs(10));
+
+        assert_eq!(evaluate(&[]), Delivery::Full);
+        assert_eq!(evaluate(&[("If-None-Match", ETAG)]), Delivery::NotModified);
+        assert_eq!(evaluate(&[("If-None-Match", "\"other\"")]), Delivery::Full);
+        assert_eq!(evaluate(&[("If-Modified-Since", &date)]), Delivery::NotModified);
+        assert_eq!(evaluate(&[("If-Modified-Since", &earlier)]), Delivery::Full);
+        // If-Modified-Since is ignored when If-None-Match is present
+        assert_eq!(
+            evaluate(&[("If-None-Match", "\"other\""), ("If-Modified-Since", &date)]),
+            Delivery::Full
+        );
+    }
+
+    #[test]
+    fn evaluate_if_range() {
+        let date = fmt_http_date(modified());
+        let earlier = fmt_http_date(modified() - Duration::from_secs(10));
+        let later = fmt_http_date(modified() + Duration::from_secs(10));
+        let range = ("Range", "bytes=0-99");
+
+        assert_eq!(evaluate(&[range]), Delivery::Partial(0, 99));
+        assert_eq!(evaluate(&[range, ("If-Range", ETAG)]), Delivery::Partial(0, 99));
+        assert_eq!(evaluate(&[range, ("If-Range", &date)]), Delivery::Partial(0, 99));
+        // Changed files are sent as a whole
+        assert_eq!(evaluate(&[range, ("If-Range", "\"other\"")]), Delivery::Full);
+        assert_eq!(evaluate(&[range, ("If-Range", &earlier)]), Delivery::Full);
+        // Dates must match Last-Modified exactly
+        assert_eq!(evaluate(&[range, ("If-Range", &later)]), Delivery::Full);
+        // If-Range requires strong comparison
+        assert_eq!(evaluate(&[range, ("If-Range", &format!("W/{}", ETAG))]), Delivery::Full);
+    }
+}
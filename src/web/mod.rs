@@ -1,6 +1,7 @@
 //! Contains Web manipulations and endpoints.
 
 pub(crate) mod endpoint;
+pub(crate) mod file;
 pub(crate) mod multipart;
 pub(crate) mod session;
 pub(crate) mod template;
@@ -17,6 +18,7 @@ use anyhow::format_err;
 use async_trait::async_trait;
 use log::{info, warn};
 use serde_json::Value as JsonValue;
+use time::Duration;
 
 use tide::{
     http::{mime, Method, Request as HttpRequest, StatusCode},
@@ -170,11 +172,12 @@ pub async fn deform_http_method<State: 'static + Send + Sync + Clone>(mut reques
 /// * Deform HTTP method with `_method`
 pub struct CsrfProtectionMiddleware {
     cipher: Aes256GcmSiv,
+    token_expiary: Duration,
 }
 
 impl CsrfProtectionMiddleware {
-    pub fn new(cipher: Aes256GcmSiv) -> CsrfProtectionMiddleware {
-        CsrfProtectionMiddleware { cipher }
+    pub fn new(cipher: Aes256GcmSiv, token_expiary: Duration) -> CsrfProtectionMiddleware {
+        CsrfProtectionMiddleware { cipher, token_expiary }
     }
 }
 
@@ -202,7 +205,7 @@ impl<State: 'static + Send + Sync + Clone> Middleware<State> for CsrfProtectionM
         // CSRF token validation
         if !ALLOWED_METHODS.contains(&request.method()) {
             match token {
-                Some(token) => match verify_csrf_token(&self.cipher, request.session(), &token) {
+                Some(token) => match verify_csrf_token(&self.cipher, request.session(), &token, self.token_expiary) {
                     Ok(()) => info!("CSRF protection succeeded"),
                     Err(e) => {
                         warn!("CSRF protection failed: {}", e);
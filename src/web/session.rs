@@ -74,6 +74,21 @@ impl RedisStore {
         })
     }
 
+    /// Checks that the connection is alive.
+    pub async fn ping(&self) -> Result<()> {
+        let mut conn = self.connection.lock().await;
+        redis::cmd("PING").query_async::<_, String>(&mut *conn).await?;
+        Ok(())
+    }
+
+    /// Asks the server to close the connection.
+    /// The store can't be used afterwards, so this is only for shutting down.
+    pub async fn close(&self) -> Result<()> {
+        let mut conn = self.connection.lock().await;
+        redis::cmd("QUIT").query_async::<_, ()>(&mut *conn).await?;
+        Ok(())
+    }
+
     /// Generates Redis key from session.
     pub fn redis_key(&self, original_key: &str) -> String {
         let mut key = self.id_header.clone();
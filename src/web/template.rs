@@ -3,10 +3,12 @@
 use crate::{
     action::session::{Common, Flash},
     application::State,
-    entity::Media as MediaEntity,
+    entity::{Media as MediaEntity, Webhook, WebhookDelivery},
+    web::endpoint::oembed,
 };
 
 use anyhow::Result;
+use serde_json::Value as JsonValue;
 use url::Url;
 use yarte::Template;
 
@@ -16,6 +18,10 @@ pub struct PageInfo {
     pub title: Option<String>,
     pub description: Option<String>,
     pub thumbnail: Option<Url>,
+    pub thumbnail_size: Option<(u32, u32)>,
+    pub thumbnail_type: Option<String>,
+    pub oembed: Option<(Url, Url)>,
+    pub structured_data: Option<String>,
 }
 
 #[allow(dead_code)]
@@ -27,6 +33,10 @@ impl PageInfo {
             title: None,
             description: None,
             thumbnail: None,
+            thumbnail_size: None,
+            thumbnail_type: None,
+            oembed: None,
+            structured_data: None,
         })
     }
 
@@ -42,12 +52,29 @@ impl PageInfo {
         self
     }
 
-    /// Sets the title.
-    pub fn with_thumbnail(mut self, thumbnail: &Url) -> PageInfo {
+    /// Sets the thumbnail with its size and media type.
+    pub fn with_thumbnail(mut self, thumbnail: &Url, size: (u32, u32), content_type: &str) -> PageInfo {
         self.thumbnail = Some(thumbnail.clone());
+        self.thumbnail_size = Some(size);
+        self.thumbnail_type = Some(content_type.to_string());
         self
     }
 
+    /// Sets JSON-LD structured data.
+    /// `<` is escaped so that the value can't close the script element.
+    pub fn with_structured_data(mut self, data: &JsonValue) -> Result<PageInfo> {
+        self.structured_data = Some(serde_json::to_string(data)?.replace('<', "\\u003c"));
+        Ok(self)
+    }
+
+    /// Sets oEmbed discovery URLs for JSON and XML.
+    pub fn with_oembed(mut self, hosted_at: &Url) -> Result<PageInfo> {
+        let json = oembed::discovery_url(hosted_at, &self.url, "json")?;
+        let xml = oembed::discovery_url(hosted_at, &self.url, "xml")?;
+        self.oembed = Some((json, xml));
+        Ok(self)
+    }
+
     /// Returns OGP page type.
     pub fn page_type(&self) -> &str {
         if self.url.path() == "/" {
@@ -87,4 +114,67 @@ pub struct MediaShow {
     pub info: PageInfo,
     pub common: Common,
     pub media: MediaEntity,
+    pub similar_list: Vec<MediaEntity>,
+}
+
+#[derive(Debug, Template)]
+#[template(path = "m/expired.html.hbs")]
+pub struct MediaExpired {
+    pub info: PageInfo,
+    pub common: Common,
+    pub hash_id: String,
+}
+
+#[derive(Debug, Template)]
+#[template(path = "trash/index.html.hbs")]
+pub struct TrashIndex {
+    pub info: PageInfo,
+    pub common: Common,
+    pub media_list: Vec<MediaEntity>,
+    pub retention_days: i64,
+}
+
+#[derive(Debug, Template)]
+#[template(path = "webhooks/index.html.hbs")]
+pub struct WebhookIndex {
+    pub info: PageInfo,
+    pub common: Common,
+    pub webhooks: Vec<Webhook>,
+    pub deliveries: Vec<WebhookDelivery>,
+    pub events: Vec<&'static str>,
+}
+
+/// An entry of media feeds.
+#[derive(Debug, Clone)]
+pub struct FeedEntry {
+    pub title: String,
+    pub summary: Option<String>,
+    pub page_url: String,
+    pub original_url: String,
+    pub original_type: String,
+    pub filesize: i32,
+    pub width: i32,
+    pub height: i32,
+    pub thumbnail_url: String,
+    pub thumbnail_size: (u32, u32),
+    pub published_rfc3339: String,
+    pub published_rfc2822: String,
+}
+
+#[derive(Debug, Template)]
+#[template(path = "feed/atom.xml.hbs")]
+pub struct AtomFeed {
+    pub info: PageInfo,
+    pub site_url: String,
+    pub updated: String,
+    pub entries: Vec<FeedEntry>,
+}
+
+#[derive(Debug, Template)]
+#[template(path = "feed/rss.xml.hbs")]
+pub struct RssFeed {
+    pub info: PageInfo,
+    pub site_url: String,
+    pub updated: String,
+    pub entries: Vec<FeedEntry>,
 }